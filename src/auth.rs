@@ -0,0 +1,171 @@
+//! OAuth token cache that is safe to share between concurrently running processes.
+//!
+//! The file format is the one yup_oauth2 uses for `persist_tokens_to_disk`, so existing
+//! `tokencache.json` files keep working.
+//!
+//! With the `keyring` feature the tokens can live in the OS credential store (macOS Keychain,
+//! Windows Credential Manager, Secret Service) instead. An existing file is moved into the
+//! keyring on the next token refresh, and the file is used whenever the keyring is unavailable.
+
+use crate::lock::{atomic_write, lock_path_for, FileLock};
+use anyhow::Context;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::PathBuf;
+use yup_oauth2::storage::{TokenInfo, TokenStorage};
+
+#[derive(Serialize, Deserialize)]
+struct CachedToken {
+    scopes: Vec<String>,
+    token: TokenInfo,
+}
+
+/// Token storage backed by a JSON file, written atomically under a lock file, or by the OS
+/// keyring if one was set with `with_keyring`.
+pub struct TokenCache {
+    path: PathBuf,
+    #[cfg(feature = "keyring")]
+    keyring: Option<keyring::Entry>,
+}
+
+impl TokenCache {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        TokenCache {
+            path: path.into(),
+            #[cfg(feature = "keyring")]
+            keyring: None,
+        }
+    }
+
+    /// Keep the tokens in the OS credential store under `service`, falling back to the file.
+    #[cfg(feature = "keyring")]
+    pub fn with_keyring(mut self, service: &str) -> Self {
+        match keyring::Entry::new(service, "oauth-tokens") {
+            Ok(entry) => self.keyring = Some(entry),
+            Err(e) => eprintln!(
+                "OS keyring unavailable ({}), using {}",
+                e,
+                self.path.display()
+            ),
+        }
+        self
+    }
+
+    /// Whether a token is stored, i.e. the next run can start without a browser login.
+    pub fn has_tokens(&self) -> bool {
+        self.load().is_ok_and(|tokens| !tokens.is_empty())
+    }
+
+    fn load(&self) -> anyhow::Result<Vec<CachedToken>> {
+        #[cfg(feature = "keyring")]
+        if let Some(entry) = &self.keyring {
+            match entry.get_password() {
+                Ok(json) => {
+                    return serde_json::from_str(&json)
+                        .context("Tokens in the OS keyring are corrupt, log in again")
+                }
+                /* Nothing there yet, the file may still have tokens to migrate */
+                Err(keyring::Error::NoEntry) => {}
+                Err(e) => eprintln!("Cannot read the OS keyring ({}), using the token file", e),
+            }
+        }
+        self.load_file()
+    }
+
+    fn load_file(&self) -> anyhow::Result<Vec<CachedToken>> {
+        let contents = match std::fs::read(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Cannot read {}", self.path.display()))
+            }
+        };
+        serde_json::from_slice(&contents).with_context(|| {
+            format!(
+                "Token cache {} is corrupt, delete it to re-authenticate",
+                self.path.display()
+            )
+        })
+    }
+
+    fn store(&self, json: &[u8]) -> anyhow::Result<()> {
+        #[cfg(feature = "keyring")]
+        if let Some(entry) = &self.keyring {
+            match entry.set_password(std::str::from_utf8(json)?) {
+                Ok(()) => {
+                    /* The keyring has everything now, don't leave a plain text copy behind */
+                    if let Err(e) = std::fs::remove_file(&self.path) {
+                        if e.kind() != io::ErrorKind::NotFound {
+                            eprintln!("Cannot remove {}: {}", self.path.display(), e);
+                        }
+                    }
+                    return Ok(());
+                }
+                Err(e) => eprintln!("Cannot write the OS keyring ({}), using the token file", e),
+            }
+        }
+        atomic_write(&self.path, json)
+            .with_context(|| format!("Cannot write {}", self.path.display()))
+    }
+}
+
+#[async_trait]
+impl TokenStorage for TokenCache {
+    async fn set(&self, scopes: &[&str], token: TokenInfo) -> anyhow::Result<()> {
+        let _lock = FileLock::acquire(lock_path_for(&self.path)).await?;
+
+        /* Re-read under the lock so we keep tokens another process stored in the meantime. */
+        let mut tokens = self.load().unwrap_or_else(|e| {
+            eprintln!("{:#}, starting a new one", e);
+            vec![]
+        });
+
+        let mut scopes: Vec<String> = scopes.iter().map(|s| s.to_string()).collect();
+        scopes.sort_unstable();
+        match tokens.iter_mut().find(|t| t.scopes == scopes) {
+            Some(cached) => cached.token = token,
+            None => tokens.push(CachedToken { scopes, token }),
+        }
+
+        self.store(&serde_json::to_vec(&tokens)?)
+    }
+
+    async fn get(&self, scopes: &[&str]) -> Option<TokenInfo> {
+        let tokens = self.load().map_err(|e| eprintln!("{:#}", e)).ok()?;
+
+        /* A token granted for a superset of the requested scopes is good enough. */
+        tokens
+            .into_iter()
+            .find(|t| scopes.iter().all(|s| t.scopes.iter().any(|c| covers(c, s))))
+            .map(|t| t.token)
+    }
+}
+
+/* Whether a token granted `granted` may be used for `requested`. Full access to an API includes
+ * its read-only scope, e.g. ".../auth/spreadsheets" covers ".../auth/spreadsheets.readonly".
+ */
+fn covers(granted: &str, requested: &str) -> bool {
+    granted == requested || requested.strip_suffix(".readonly") == Some(granted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const READ_WRITE: &str = "https://www.googleapis.com/auth/spreadsheets";
+    const READ_ONLY: &str = "https://www.googleapis.com/auth/spreadsheets.readonly";
+
+    #[test]
+    fn full_access_covers_read_only() {
+        assert!(covers(READ_WRITE, READ_WRITE));
+        assert!(covers(READ_ONLY, READ_ONLY));
+        assert!(covers(READ_WRITE, READ_ONLY));
+    }
+
+    #[test]
+    fn read_only_does_not_cover_full_access() {
+        assert!(!covers(READ_ONLY, READ_WRITE));
+        assert!(!covers("https://www.googleapis.com/auth/drive", READ_ONLY));
+    }
+}
@@ -0,0 +1,61 @@
+//! Helpers for `spreadsheets.batchUpdate`.
+//!
+//! The generated `Request` type has one optional field per request kind and no `Default`, so we
+//! write requests in their JSON form as documented by Google and convert them.
+
+use crate::usage::ApiUsage;
+use serde_json::{json, Value};
+use sheets::spreadsheets::Spreadsheets;
+use sheets::types::{
+    BatchUpdateSpreadsheetRequest, BatchUpdateSpreadsheetResponse, Request, Spreadsheet,
+};
+use sheets::ClientError;
+
+/// Turn the JSON form of a single request, e.g. `{"deleteSheet": {"sheetId": 42}}`, into a
+/// `Request`. Panics on malformed JSON, which is a programming error.
+pub fn request(json: Value) -> Request {
+    serde_json::from_value(json).expect("Invalid batchUpdate request")
+}
+
+/// A zero-based, half-open grid range as used by batchUpdate requests.
+pub fn grid_range(
+    sheet_id: i64,
+    rows: std::ops::Range<usize>,
+    columns: std::ops::Range<usize>,
+) -> Value {
+    json!({
+        "sheetId": sheet_id,
+        "startRowIndex": rows.start,
+        "endRowIndex": rows.end,
+        "startColumnIndex": columns.start,
+        "endColumnIndex": columns.end,
+    })
+}
+
+/// Numeric ID of the tab called `title`.
+pub fn sheet_id(spreadsheet: &Spreadsheet, title: &str) -> Option<i64> {
+    spreadsheet
+        .sheets
+        .iter()
+        .filter_map(|sheet| sheet.properties.as_ref())
+        .find(|props| props.title == title)
+        .map(|props| props.sheet_id)
+}
+
+/// Send `requests` in one batch. The API applies either all of them or none.
+pub async fn batch_update(
+    sheets: &Spreadsheets,
+    spreadsheet: &str,
+    requests: Vec<Request>,
+    usage: &mut ApiUsage,
+) -> Result<BatchUpdateSpreadsheetResponse, ClientError> {
+    let body = BatchUpdateSpreadsheetRequest {
+        include_spreadsheet_in_response: None,
+        requests,
+        response_include_grid_data: None,
+        response_ranges: vec![],
+    };
+    let response = sheets.batch_update(spreadsheet, &body).await?;
+    usage.record_write(&body, &response.body);
+    Ok(response.body)
+}
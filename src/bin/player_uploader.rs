@@ -1,51 +1,560 @@
-use sheets::{
-    self,
-    types::{
-        ClearValuesRequest, DateTimeRenderOption, Dimension, ValueInputOption, ValueRange,
-        ValueRenderOption,
-    },
-};
-use std::time::Instant;
+use std::collections::BTreeMap;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
 use table_extract::Table;
-use tokio;
 use yup_oauth2::{InstalledFlowAuthenticator, InstalledFlowReturnMethod};
-use clap::Parser;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use fm_data::auth::TokenCache;
+use fm_data::format::{format_squad_sheet, number_formats};
+use fm_data::identity::PlayerKey;
+use fm_data::layout::LayoutFingerprint;
+use fm_data::lock::{atomic_write, FileLock};
+use fm_data::metadata::{self, RunMetadata};
+use fm_data::passthrough::Passthrough;
+use fm_data::profile::RunProfile;
+use fm_data::prune::prune_departed;
+use fm_data::settings::{read_settings, SETTINGS_SHEET};
+use fm_data::snapshot::SquadSnapshot;
+use fm_data::upload::{ClearMode, DataUploader, UploadReport, DEFAULT_CHUNK_ROWS};
+use fm_data::usage::ApiUsage;
+use sheets::types::{Sheet, Spreadsheet};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+
+/* The player table as read from FM. `headers` is empty if the input had no header line. */
+struct InputTable {
+    headers: Vec<String>,
+    rows: Vec<Vec<String>>,
+    /// The raw input, for the run metadata
+    source: Vec<u8>,
+    /// When the input file was written, unknown for the clipboard
+    modified: Option<SystemTime>,
+}
 
-fn read_table(html_file: &str) -> Table {
-    Table::find_first(&std::fs::read_to_string(html_file).unwrap()).unwrap()
+fn read_table(html_file: &str) -> InputTable {
+    InputTable {
+        modified: std::fs::metadata(html_file).and_then(|m| m.modified()).ok(),
+        ..parse_html_table(std::fs::read_to_string(html_file).unwrap())
+    }
+}
+
+fn parse_html_table(html: String) -> InputTable {
+    let table = Table::find_first(&html).expect("No table found in the HTML input");
+    let mut headers: Vec<(&String, &usize)> = table.headers().iter().collect();
+    headers.sort_by_key(|(_, pos)| **pos);
+    InputTable {
+        headers: headers.into_iter().map(|(name, _)| name.clone()).collect(),
+        rows: table.iter().map(|row| row.as_slice().to_vec()).collect(),
+        source: html.into_bytes(),
+        modified: None,
+    }
+}
+
+/* FM can also copy a whole view as text (Ctrl+A, Ctrl+C). That gives us tab separated lines,
+ * usually starting with a header line. We drop the header just like table_extract does for the
+ * HTML export and make sure all rows have the same number of columns.
+ */
+#[cfg(feature = "clipboard")]
+fn parse_tab_separated(text: &str) -> InputTable {
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(nr, line)| {
+            let cells: Vec<String> = line.split('\t').map(|c| c.trim().to_string()).collect();
+            (nr + 1, cells)
+        });
+
+    let (_, first) = lines.next().expect("Clipboard text contains no data");
+    let width = first.len();
+
+    /* A header line has no numbers in it, while every player row has at least its attributes. */
+    let is_header = first.iter().all(|c| c.parse::<f64>().is_err());
+    let mut headers = vec![];
+    let mut rows = vec![];
+    if is_header {
+        headers = first;
+    } else {
+        rows.push(first);
+    }
+
+    for (nr, cells) in lines {
+        if cells.len() != width {
+            panic!(
+                "Clipboard line {} has {} columns, expected {}. Did the copy include the whole view?",
+                nr,
+                cells.len(),
+                width
+            );
+        }
+        rows.push(cells);
+    }
+    InputTable {
+        headers,
+        rows,
+        source: text.as_bytes().to_vec(),
+        modified: None,
+    }
+}
+
+#[cfg(feature = "clipboard")]
+fn read_clipboard_table() -> InputTable {
+    let text = arboard::Clipboard::new()
+        .and_then(|mut clipboard| clipboard.get_text())
+        .expect("Could not read text from the clipboard");
+    parse_tab_separated(&text)
+}
+
+#[cfg(not(feature = "clipboard"))]
+fn read_clipboard_table() -> InputTable {
+    panic!("fm_google_up was built without the 'clipboard' feature")
 }
 
 static SPREAD: &str = "1ZrBTdlMlGaLD6LhMs948YvZ41NE71mcy7jhmygJU2Bc";
 static CREDS: &str = "/Users/bjoernd/Downloads/client_secret_159115558609-mkiidqjgej4ds1615oukp125c4nn2qcf.apps.googleusercontent.com.json";
+static TOKEN_CACHE: &str = "tokencache.json";
+static SCOPE_READ_WRITE: &str = "https://www.googleapis.com/auth/spreadsheets";
+static SCOPE_READ_ONLY: &str = "https://www.googleapis.com/auth/spreadsheets.readonly";
 static HTML: &str =
     "/Users/bjoernd/Library/Application Support/Sports Interactive/Football Manager 2024/bd.html";
 
-#[derive(Parser, Debug)]
-#[command(version, about="Upload FM Player data to Google sheets", long_about = None)]
+/* Which character separates the integer and fractional part of numbers in the input. */
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum DecimalSeparator {
+    /// Guess from the data
+    Auto,
+    /// 12.5
+    Dot,
+    /// 12,5 (German, French, ... locales)
+    Comma,
+}
+
+impl DecimalSeparator {
+    fn as_char(self) -> char {
+        match self {
+            DecimalSeparator::Comma => ',',
+            _ => '.',
+        }
+    }
+
+    /* The thousands separator that goes with this decimal separator */
+    fn grouping_char(self) -> char {
+        match self {
+            DecimalSeparator::Comma => '.',
+            _ => ',',
+        }
+    }
+
+    /* Sheets interprets user entered values in the spreadsheet locale, e.g. "de_DE". */
+    fn for_locale(locale: &str) -> DecimalSeparator {
+        const COMMA_LANGUAGES: &[&str] = &[
+            "cs", "da", "de", "es", "fi", "fr", "hu", "id", "it", "nb", "nl", "pl", "pt", "ro",
+            "ru", "sv", "tr", "uk",
+        ];
+        /* Countries writing a dot decimal although their language mostly doesn't */
+        const DOT_LOCALES: &[&str] = &[
+            "de_CH", "de_LI", "it_CH", "es_419", "es_DO", "es_GT", "es_HN", "es_MX", "es_NI",
+            "es_PA", "es_PE", "es_PR", "es_SV", "es_US",
+        ];
+        let locale = locale.replace('-', "_");
+        let language = locale.split('_').next().unwrap_or_default();
+        if COMMA_LANGUAGES.contains(&language) && !DOT_LOCALES.contains(&locale.as_str()) {
+            DecimalSeparator::Comma
+        } else {
+            DecimalSeparator::Dot
+        }
+    }
+}
+
+/* Round the unsigned decimal `int`.`frac` to `places` fractional digits, half away from zero.
+ * Works on the digits so that ("2", "675") gives ("2", "68"), which f64 would get wrong.
+ */
+fn round_decimal(int: &str, frac: &str, places: usize) -> (String, String) {
+    if frac.len() <= places {
+        return (int.to_string(), frac.to_string());
+    }
+    let mut digits = format!("{}{}", int, &frac[..places]).into_bytes();
+    if frac.as_bytes()[places] >= b'5' {
+        let mut i = digits.len();
+        loop {
+            if i == 0 {
+                digits.insert(0, b'1');
+                break;
+            }
+            i -= 1;
+            if digits[i] == b'9' {
+                digits[i] = b'0';
+            } else {
+                digits[i] += 1;
+                break;
+            }
+        }
+    }
+    let digits = String::from_utf8(digits).unwrap();
+    let (int, frac) = digits.split_at(digits.len() - places);
+    (int.to_string(), frac.to_string())
+}
+
+/* "12,5" with from=Comma, to=Dot -> Some("12.5"). With `places` the number is also rounded, e.g.
+ * "6,87" to one place -> Some("6.9"). Integers with the thousands separator that goes with `from`
+ * lose it, e.g. "1.234" from=Comma -> Some("1234"). Anything else -> None.
+ */
+fn convert_decimal(
+    cell: &str,
+    from: DecimalSeparator,
+    to: DecimalSeparator,
+    places: Option<usize>,
+) -> Option<String> {
+    if is_thousands_grouped(cell, from.grouping_char()) {
+        return Some(cell.replace(from.grouping_char(), ""));
+    }
+    let (int, frac) = cell.split_once(from.as_char())?;
+    let (sign, digits) = match int.strip_prefix('-') {
+        Some(digits) => ("-", digits),
+        None => ("", int),
+    };
+    let is_number = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+    if !is_number(digits) || !is_number(frac) {
+        return None;
+    }
+    let (digits, frac) = match places {
+        Some(places) => round_decimal(digits, frac, places),
+        None => (digits.to_string(), frac.to_string()),
+    };
+    /* "-0,04" rounded to one place is "0.0", not "-0.0" */
+    let sign = if format!("{}{}", digits, frac).bytes().all(|b| b == b'0') { "" } else { sign };
+    if frac.is_empty() {
+        Some(format!("{}{}", sign, digits))
+    } else {
+        Some(format!("{}{}{}{}", sign, digits, to.as_char(), frac))
+    }
+}
+
+/* "1,500" or "-12,000,000" with separator ',', "1.234" with '.': an integer with thousands
+ * separators, which could just as well be a decimal number.
+ */
+fn is_thousands_grouped(cell: &str, separator: char) -> bool {
+    let groups: Vec<&str> = cell.strip_prefix('-').unwrap_or(cell).split(separator).collect();
+    groups.len() > 1
+        && (1..=3).contains(&groups[0].len())
+        && groups[1..].iter().all(|g| g.len() == 3)
+        && groups.iter().all(|g| g.chars().all(|c| c.is_ascii_digit()))
+}
+
+/* Comma decimals win if we see any of them and no dot decimals at all. Cells that may also be
+ * thousands separated integers don't count, so neither "1,500" nor "1.234" decide anything.
+ */
+fn detect_decimal_separator(table: &[Vec<String>]) -> DecimalSeparator {
+    let cells = || {
+        table
+            .iter()
+            .flatten()
+            .filter(|c| !is_thousands_grouped(c, ',') && !is_thousands_grouped(c, '.'))
+    };
+    let is_decimal = |c: &String, sep| convert_decimal(c, sep, sep, None).is_some();
+    let has_dot = cells().any(|c| is_decimal(c, DecimalSeparator::Dot));
+    let has_comma = cells().any(|c| is_decimal(c, DecimalSeparator::Comma));
+    if has_comma && !has_dot {
+        DecimalSeparator::Comma
+    } else {
+        DecimalSeparator::Dot
+    }
+}
+
+/* Some minor massaging of the input data to suit the Google Sheet processing. Decimal numbers are
+ * rewritten from the separator used in the input to the one the spreadsheet locale expects, and
+ * rounded to `places` fractional digits if given.
+ */
+fn massage_table(
+    table: &[Vec<String>],
+    input_decimals: DecimalSeparator,
+    sheet_decimals: DecimalSeparator,
+    places: Option<usize>,
+) -> Vec<Vec<String>> {
+    let input_decimals = match input_decimals {
+        DecimalSeparator::Auto => detect_decimal_separator(table),
+        d => d,
+    };
+
+    let mut matrix = vec![];
+    for row in table {
+        let mut line = vec![];
+        for cell in row {
+            let value = match cell.as_str() {
+                "Left" | "Left Only" => "l",
+                "Right" | "Right Only" => "r",
+                "Either" => "rl",
+                "-" => "0",
+                _ => cell,
+            };
+            let value = if input_decimals != sheet_decimals || places.is_some() {
+                convert_decimal(value, input_decimals, sheet_decimals, places)
+            } else {
+                None
+            }
+            .unwrap_or_else(|| String::from(value));
+            line.push(value)
+        }
+        matrix.push(line);
+    }
+    matrix
+}
+
+#[derive(Parser, Debug, Clone)]
+#[command(name = "fm_google_up", version, about="Upload FM Player data to Google sheets", long_about = None)]
 struct CLIArguments {
     #[arg(short,long,default_value_t = SPREAD.to_string())]
     spreadsheet: String,
     #[arg(short,long,default_value_t = CREDS.to_string())]
     credfile: String,
     #[arg(short,long,default_value_t = HTML.to_string())]
-    input: String
+    input: String,
+    /// Read the player table from a text copy of an FM view in the clipboard instead of --input
+    #[arg(long)]
+    from_clipboard_text: bool,
+    /// First spreadsheet row holding player data; rows above it are headers and left untouched
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u32).range(1..))]
+    data_start_row: u32,
+    /// Decimal separator used by numbers in the input
+    #[arg(long, value_enum, default_value_t = DecimalSeparator::Auto)]
+    decimal_separator: DecimalSeparator,
+    /// Round decimal numbers (ratings, ...) to this many fractional digits before uploading
+    #[arg(long)]
+    decimals: Option<usize>,
+    /// Upload even if the input columns differ from the last successful upload
+    #[arg(long)]
+    accept_layout_change: bool,
+    /// Upload even if the input file is older than the export of the last upload (needs
+    /// --metadata-cell)
+    #[arg(long, requires = "metadata_cell")]
+    accept_stale_input: bool,
+    /// What to wipe from the old data area before uploading
+    #[arg(long, value_enum, default_value_t = ClearMode::Values)]
+    clear_mode: ClearMode,
+    /// Upload via a hidden staging tab so a failed upload leaves the Squad sheet untouched
+    #[arg(long)]
+    staged: bool,
+    /// Print wall time and throughput of each stage at the end of the run
+    #[arg(long)]
+    profile_run: bool,
+    /// Maximum rows per write request; larger tables are uploaded in chunks
+    #[arg(long, default_value_t = DEFAULT_CHUNK_ROWS)]
+    chunk_rows: usize,
+    /// After uploading, freeze the header rows, fit column widths and set number formats
+    #[arg(long)]
+    format_sheet: bool,
+    /// Cell (e.g. Settings!D1) of a block two columns wide and four rows high (D1:E4) recording
+    /// the last upload time, tool version, input hash and input modification time
+    #[arg(long)]
+    metadata_cell: Option<String>,
+    /// Columns right of the data area (e.g. AY:BD) whose contents stay with their player
+    #[arg(long)]
+    passthrough_columns: Option<String>,
+    /// Input columns identifying a player, name first (e.g. Name,Age or Name,UID); used to
+    /// match rows for pass-through columns and prune
+    #[arg(long, value_delimiter = ',', default_value = "Name")]
+    key_headers: Vec<String>,
+    /// Run on bundled sample data and print the rows instead of uploading; needs no Google
+    /// account or FM installation
+    #[arg(long)]
+    demo: bool,
+    /// Seconds to wait for another fm_google_up run on the same spreadsheet to finish
+    #[arg(long, default_value_t = 60)]
+    lock_timeout: u64,
+    /// Write a JSON summary of the run (counts, warnings, API usage) to this file when it
+    /// succeeds; an old summary is removed when the run starts
+    #[arg(long)]
+    summary_file: Option<PathBuf>,
+    /// Shell command run before uploading, with the rows to upload as JSON on stdin; a
+    /// non-zero exit status cancels the upload
+    #[arg(long)]
+    pre_upload_hook: Option<String>,
+    /// Shell command run after a successful upload, with the run summary as JSON on stdin
+    #[arg(long)]
+    post_upload_hook: Option<String>,
+    /// Give up if a step talking to the spreadsheet (one request, or the whole upload) takes
+    /// longer than this many seconds, e.g. on a flaky connection. Implies --staged so giving up
+    /// never leaves the Squad sheet half written. Hooks and prune are not subject to it
+    #[arg(long)]
+    timeout: Option<u64>,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[tokio::main]
-async fn main() {
-    let start_time = Instant::now();
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Print shell completions for bash/zsh/fish/... to stdout
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// Print the man page (roff format) to stdout
+    Manpage,
+    /// Check credentials, input, settings and spreadsheet access without uploading anything
+    Check,
+    /// Keep running and upload the input whenever it changed, checking on a cron schedule
+    Daemon {
+        /// When to look for a new export, with seconds, e.g. "0 */10 * * * *" for every 10 minutes
+        #[arg(long)]
+        schedule: String,
+        /// Where to keep the daemon's state (last check, last upload, last error) as JSON
+        #[arg(long, default_value = "fm_google_up-daemon.json")]
+        status_file: PathBuf,
+    },
+    /// Download the Squad sheet into a local JSON file for offline use
+    Snapshot {
+        /// Where to store the snapshot, e.g. squad.json
+        out: PathBuf,
+    },
+    /// Remove players who are no longer in the input from the Squad sheet instead of uploading
+    Prune {
+        /// Only list the players that would be removed
+        #[arg(long)]
+        dry_run: bool,
+        /// Append the removed rows to this tab, e.g. "Alumni"
+        #[arg(long)]
+        archive_to: Option<String>,
+    },
+}
 
-    let cli = CLIArguments::parse();
+/* Compare the input columns against the layout of the last successful upload to this
+ * spreadsheet. Returns the fingerprint to store once the upload went through, if any.
+ */
+fn check_layout(
+    headers: &[String],
+    path: &Path,
+    accept_change: bool,
+) -> Option<LayoutFingerprint> {
+    if headers.is_empty() {
+        println!("Input has no header line, skipping layout check");
+        return None;
+    }
 
-    /* This is how we OAuth today.
-     *   1. Create a new OAuth json in Google Cloud console.
-     *   2. Download OAuth config JSON (aka CREDS here)
-     *   3. Read the secrets into yup_oauth2...
-     */
-    let secret = yup_oauth2::read_application_secret(cli.credfile)
-        .await
-        .expect("JSON file not found");
+    let current = LayoutFingerprint::new(headers);
+    let stored = LayoutFingerprint::load(path).expect("Cannot read stored layout fingerprint");
+    match stored {
+        None => Some(current),
+        Some(stored) => {
+            let changes = stored.diff(&current);
+            if changes.is_empty() {
+                None
+            } else if accept_change {
+                println!("Accepting layout change:\n  {}", changes.join("\n  "));
+                Some(current)
+            } else {
+                panic!(
+                    "Input columns changed since the last upload:\n  {}\nCheck the FM view and \
+                     re-run with --accept-layout-change if the Squad sheet matches the new layout.",
+                    changes.join("\n  ")
+                );
+            }
+        }
+    }
+}
+
+/* Fill in options from the spreadsheet's Settings tab unless they were given on the command
+ * line. Unknown keys are reported so typos don't go unnoticed.
+ */
+/* Returns the keys it doesn't know, which are ignored, or what is wrong with a value. `check`
+ * runs this on a copy of the arguments to find settings a real run would trip over.
+ */
+fn apply_settings<'a>(
+    cli: &mut CLIArguments,
+    matches: &ArgMatches,
+    settings: &'a BTreeMap<String, String>,
+) -> Result<Vec<&'a str>, String> {
+    let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    let invalid = |key: &str, value: &str| {
+        format!("Invalid value '{}' for '{}' in the {} tab", value, key, SETTINGS_SHEET)
+    };
+
+    let mut unknown = vec![];
+    for (key, value) in settings {
+        match key.as_str() {
+            "data_start_row" => {
+                if !from_cli(key) {
+                    cli.data_start_row = match value.parse() {
+                        Ok(row) if row >= 1 => row,
+                        _ => return Err(invalid(key, value)),
+                    };
+                }
+            }
+            "decimal_separator" => {
+                if !from_cli(key) {
+                    cli.decimal_separator = DecimalSeparator::from_str(value, true)
+                        .map_err(|_| invalid(key, value))?;
+                }
+            }
+            "decimals" => {
+                if !from_cli(key) {
+                    cli.decimals = Some(value.parse().map_err(|_| invalid(key, value))?);
+                }
+            }
+            _ => unknown.push(key.as_str()),
+        }
+    }
+    Ok(unknown)
+}
+
+/* Packaging helpers: these only look at the CLI definition and never touch Google. Returns false
+ * for commands that need the spreadsheet.
+ */
+fn run_offline_command(cmd: &Command) -> bool {
+    let mut clap_cmd = CLIArguments::command();
+    match cmd {
+        Command::Completions { shell } => {
+            let name = clap_cmd.get_name().to_string();
+            clap_complete::generate(*shell, &mut clap_cmd, name, &mut std::io::stdout());
+        }
+        Command::Manpage => {
+            clap_mangen::Man::new(clap_cmd)
+                .render(&mut std::io::stdout())
+                .expect("Failed to render man page");
+        }
+        Command::Check
+        | Command::Daemon { .. }
+        | Command::Snapshot { .. }
+        | Command::Prune { .. } => return false,
+    }
+    true
+}
+
+/* The whole local part of the pipeline on canned data, printing what would be uploaded. */
+fn run_demo(decimals: DecimalSeparator, places: Option<usize>, first_row: u32) {
+    let table = parse_html_table(include_str!("../../demo/squad.html").to_string());
+    println!("Demo input: {} players, columns: {}", table.rows.len(), table.headers.join(", "));
+
+    let matrix = massage_table(&table.rows, decimals, DecimalSeparator::Dot, places);
+    println!("Rows as they would be uploaded to Squad!A{}:", first_row);
+    for row in &matrix {
+        println!("  {}", row.join("\t"));
+    }
+}
 
+fn player_key(table: &InputTable, key_headers: &[String]) -> PlayerKey {
+    let key = PlayerKey::from_headers(&table.headers, key_headers)
+        .expect("Cannot identify players in the input");
+    key.check_unique(&table.rows, "the input")
+        .expect("Cannot identify players in the input");
+    key
+}
+
+fn token_cache() -> TokenCache {
+    let cache = TokenCache::new(TOKEN_CACHE);
+    #[cfg(feature = "keyring")]
+    let cache = cache.with_keyring("fm_google_up");
+    cache
+}
+
+/* Log in and create the sheets client. With a cached token this needs no user interaction. */
+async fn connect(
+    secret: yup_oauth2::ApplicationSecret,
+    scopes: &[&str],
+) -> Result<sheets::spreadsheets::Spreadsheets, yup_oauth2::Error> {
     /* Here we build an Authenticator that will either use a cached token or redirect the user to
      * a Google page asking to confirm authorization. The fancy new thing here is the HTTPRedirect
      * return method, which means the auth page will redirect to a local HTTP socket and thus signal
@@ -55,15 +564,11 @@ async fn main() {
         secret.clone(),
         InstalledFlowReturnMethod::HTTPRedirect,
     )
-    .persist_tokens_to_disk("tokencache.json")
+    .with_storage(Box::new(token_cache()))
     .build()
-    .await
-    .unwrap();
-
-    /* Here we define what we want to access. In our case this is Spreadsheet access only. */
-    let scopes = &["https://www.googleapis.com/auth/spreadsheets"];
+    .await?;
 
-    let t = auth.token(scopes).await.unwrap();
+    let t = auth.token(scopes).await?;
     println!("Got access token");
 
     /* Create the sheets client that we will use for our requests below. */
@@ -75,64 +580,874 @@ async fn main() {
         t.token().unwrap(),
     );
 
-    let s = sheets::spreadsheets::Spreadsheets { client: sheet_c };
+    Ok(sheets::spreadsheets::Spreadsheets { client: sheet_c })
+}
 
-    /* Spreadsheet metadata */
-    let sc = s.get(&cli.spreadsheet, false, &[]).await.unwrap();
-    println!("Connected to spreadsheet {}", sc.body.spreadsheet_id);
+fn squad_tab(spreadsheet: &Spreadsheet) -> Option<&Sheet> {
+    spreadsheet
+        .sheets
+        .iter()
+        .find(|sheet| sheet.properties.as_ref().is_some_and(|p| p.title == "Squad"))
+}
 
-    /* Read our table from the input HTML file */
-    let table = read_table(&cli.input);
-    println!("Got table {:?}", table);
+/* The values API reads and writes all rows regardless of filters, so a filter on the Squad sheet
+ * doesn't change what we read. It does hide rows we overwrite or prune, though. Filter views are
+ * per user and don't hide anything for others, so only the basic filter is worth a warning.
+ */
+static FILTER_WARNING: &str =
+    "the Squad sheet has an active filter; rows it hides are still overwritten and pruned";
 
-    /* Clear spreadsheet target area */
-    s.values_clear(&cli.spreadsheet, "Squad!A2:AX58", &ClearValuesRequest {})
-        .await
-        .expect("Error clearing data.");
+#[derive(Clone, Copy, PartialEq)]
+enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
 
-    println!("Cleared old data");
+/* One line of the `check` checklist, colored if stdout is a terminal */
+fn report(status: CheckStatus, what: &str, detail: &str) {
+    let (label, color) = match status {
+        CheckStatus::Pass => ("PASS", "32"),
+        CheckStatus::Warn => ("WARN", "33"),
+        CheckStatus::Fail => ("FAIL", "31"),
+    };
+    if std::io::stdout().is_terminal() {
+        println!("\x1b[{}m{}\x1b[0m  {:<14} {}", color, label, what, detail);
+    } else {
+        println!("{}  {:<14} {}", label, what, detail);
+    }
+}
 
-    /* Some minor massaging of the input data to suit the Google Sheet processing */
-    let mut matrix = vec![];
-    for row in &table {
-        let mut line = vec![];
-        for cell in row {
-            let value = match cell.as_str() {
-                "Left" | "Left Only" => "l",
-                "Right" | "Right Only" => "r",
-                "Either" => "rl",
-                "-" => "0",
-                _ => cell,
+/* Everything a real run needs, checked in one go so problems show up before a long run instead
+ * of as a panic halfway through. Returns whether nothing failed.
+ */
+async fn run_check(cli: &CLIArguments, matches: &ArgMatches) -> bool {
+    let mut statuses = Vec::new();
+    let mut check = |status: CheckStatus, what: &str, detail: String| {
+        report(status, what, &detail);
+        statuses.push(status);
+    };
+
+    let secret = match yup_oauth2::read_application_secret(&cli.credfile).await {
+        Ok(secret) => {
+            check(CheckStatus::Pass, "credentials", cli.credfile.clone());
+            Some(secret)
+        }
+        Err(e) => {
+            check(CheckStatus::Fail, "credentials", format!("{}: {}", cli.credfile, e));
+            None
+        }
+    };
+
+    let have_token = token_cache().has_tokens();
+    if have_token {
+        check(CheckStatus::Pass, "token cache", "token stored".into());
+    } else {
+        check(
+            CheckStatus::Warn,
+            "token cache",
+            "no token stored, the next run asks you to log in".into(),
+        );
+    }
+
+    let table = if cli.from_clipboard_text {
+        check(CheckStatus::Warn, "input", "reading from the clipboard, not checked".into());
+        None
+    } else {
+        match std::fs::read_to_string(&cli.input) {
+            Ok(html) if Table::find_first(&html).is_some() => {
+                let table = parse_html_table(html);
+                check(
+                    CheckStatus::Pass,
+                    "input",
+                    format!("{}: {} rows, {} columns", cli.input, table.rows.len(), table.headers.len()),
+                );
+                Some(table)
+            }
+            Ok(_) => {
+                check(CheckStatus::Fail, "input", format!("{}: no table found", cli.input));
+                None
+            }
+            Err(e) => {
+                check(CheckStatus::Fail, "input", format!("{}: {}", cli.input, e));
+                None
+            }
+        }
+    };
+
+    if let Some(table) = &table {
+        /* Only pass-through columns need to tell players apart, a plain upload doesn't */
+        let key = PlayerKey::from_headers(&table.headers, &cli.key_headers)
+            .and_then(|key| key.check_unique(&table.rows, "the input"));
+        match key {
+            Ok(()) => check(CheckStatus::Pass, "player key", cli.key_headers.join("+")),
+            Err(e) if cli.passthrough_columns.is_some() => {
+                check(CheckStatus::Fail, "player key", e.to_string())
+            }
+            Err(e) => check(
+                CheckStatus::Warn,
+                "player key",
+                format!("{} (only needed for pass-through columns and prune)", e),
+            ),
+        }
+
+        let layout_file = PathBuf::from(format!("layout-{}.json", cli.spreadsheet));
+        let current = LayoutFingerprint::new(&table.headers);
+        match LayoutFingerprint::load(&layout_file) {
+            Ok(Some(stored)) if stored.diff(&current).is_empty() => {
+                check(CheckStatus::Pass, "layout", "matches the last upload".into())
+            }
+            Ok(Some(stored)) => check(
+                CheckStatus::Warn,
+                "layout",
+                format!("changed since the last upload: {}", stored.diff(&current).join(", ")),
+            ),
+            Ok(None) => check(CheckStatus::Pass, "layout", "no previous upload".into()),
+            Err(e) => check(CheckStatus::Fail, "layout", format!("{}: {}", layout_file.display(), e)),
+        }
+    }
+
+    match secret {
+        Some(_) if !have_token => check(
+            CheckStatus::Warn,
+            "spreadsheet",
+            "not checked, needs a login first".into(),
+        ),
+        None => check(CheckStatus::Fail, "spreadsheet", "not checked, no credentials".into()),
+        Some(secret) => {
+            let mut usage = ApiUsage::default();
+            let reachable = match connect(secret, &[SCOPE_READ_ONLY]).await {
+                Ok(s) => match s.get(&cli.spreadsheet, false, &[]).await {
+                    Ok(sc) => Ok((s, sc.body)),
+                    Err(e) => Err(e.to_string()),
+                },
+                Err(e) => Err(e.to_string()),
             };
-            line.push(String::from(value))
+            match reachable {
+                Ok((s, sc)) => {
+                    let title = sc.properties.as_ref().map(|p| p.title.clone()).unwrap_or_default();
+                    check(CheckStatus::Pass, "spreadsheet", format!("'{}'", title));
+
+                    match squad_tab(&sc) {
+                        Some(tab) if tab.basic_filter.is_some() => {
+                            check(CheckStatus::Warn, "Squad tab", FILTER_WARNING.into())
+                        }
+                        Some(_) => check(CheckStatus::Pass, "Squad tab", "present".into()),
+                        None => check(CheckStatus::Fail, "Squad tab", "missing".into()),
+                    }
+
+                    match read_settings(&s, &sc, &mut usage).await {
+                        Ok(settings) => match apply_settings(&mut cli.clone(), matches, &settings) {
+                            Ok(unknown) if unknown.is_empty() => {
+                                check(CheckStatus::Pass, "settings", format!("{} keys", settings.len()))
+                            }
+                            Ok(unknown) => check(
+                                CheckStatus::Warn,
+                                "settings",
+                                format!("unknown keys: {}", unknown.join(", ")),
+                            ),
+                            Err(e) => check(CheckStatus::Fail, "settings", e),
+                        },
+                        Err(e) => check(CheckStatus::Fail, "settings", e.to_string()),
+                    }
+                }
+                Err(e) => check(CheckStatus::Fail, "spreadsheet", e),
+            }
         }
-        matrix.push(line);
     }
 
-    let new_range = format!("Squad!A2:AX{}", matrix.len() + 1);
-    let update_body = ValueRange {
-        values: matrix,
-        major_dimension: Some(Dimension::Rows),
-        range: new_range.clone(),
+    !statuses.contains(&CheckStatus::Fail)
+}
+
+#[tokio::main]
+async fn main() {
+    let start_time = Instant::now();
+
+    let matches = CLIArguments::command().get_matches();
+    let cli = CLIArguments::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    if cli.command.as_ref().is_some_and(run_offline_command) {
+        return;
+    }
+
+    if cli.demo {
+        run_demo(cli.decimal_separator, cli.decimals, cli.data_start_row);
+        return;
+    }
+
+    if matches!(cli.command, Some(Command::Check)) {
+        if !run_check(&cli, &matches).await {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Daemon {
+        schedule,
+        status_file,
+    }) = &cli.command
+    {
+        run_daemon(&cli, &matches, schedule, status_file).await;
+        return;
+    }
+
+    run(cli, matches).await;
+
+    println!(
+        "Program finished in {} ms",
+        start_time.elapsed().as_millis()
+    );
+}
+
+/* One run against the spreadsheet, from taking the lock to the last write */
+async fn run(cli: CLIArguments, matches: ArgMatches) {
+    /* Only one run per spreadsheet at a time. The lock lives in the temp directory so runs from
+     * different working directories see each other.
+     */
+    let lock_file = std::env::temp_dir().join(format!("fm_google_up-{}.lock", cli.spreadsheet));
+    let _lock = FileLock::acquire_with(&lock_file, Duration::from_secs(cli.lock_timeout))
+        .await
+        .expect("Another upload to this spreadsheet is in progress");
+
+    let mut profile = RunProfile::new(cli.profile_run);
+    let stage_start = Instant::now();
+
+    /* This is how we OAuth today.
+     *   1. Create a new OAuth json in Google Cloud console.
+     *   2. Download OAuth config JSON (aka CREDS here)
+     *   3. Read the secrets into yup_oauth2...
+     */
+    let secret = yup_oauth2::read_application_secret(&cli.credfile)
+        .await
+        .expect("JSON file not found");
+
+    /* Here we define what we want to access. In our case this is Spreadsheet access only, and
+     * only reading for runs that don't change anything.
+     */
+    let read_only = matches!(
+        cli.command,
+        Some(Command::Snapshot { .. } | Command::Prune { dry_run: true, .. })
+    );
+    let scopes = if read_only { &[SCOPE_READ_ONLY] } else { &[SCOPE_READ_WRITE] };
+
+    let s = connect(secret, scopes).await.unwrap();
+    profile.record("authenticate", stage_start, None);
+
+    sync_spreadsheet(s, cli, &matches, profile).await;
+}
+
+/* Wait for one step talking to Google, giving up after --timeout seconds. A stuck connection is
+ * the only reason for these to take long. Dropping a step halfway is fine as long as it is a
+ * single request or a staged upload, which only touches the Squad sheet in its last request.
+ * Anything else, like prune archiving players before deleting them, must not go through here.
+ */
+async fn deadline<T>(timeout: Option<u64>, step: impl std::future::Future<Output = T>) -> T {
+    match timeout {
+        Some(secs) => tokio::time::timeout(Duration::from_secs(secs), step)
+            .await
+            .unwrap_or_else(|_| panic!("No answer from Google within {} seconds, giving up", secs)),
+        None => step.await,
+    }
+}
+
+#[derive(Serialize, Default)]
+struct DaemonStatus {
+    pid: u32,
+    last_check: Option<String>,
+    next_check: Option<String>,
+    last_upload: Option<String>,
+    /// Modification time of the export last uploaded
+    input_modified: Option<String>,
+    last_error: Option<String>,
+}
+
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn log(message: &str) {
+    println!("{} daemon: {}", timestamp(Utc::now()), message);
+}
+
+/* Resolves on SIGTERM (service managers) or Ctrl+C */
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut term = signal(SignalKind::terminate()).expect("Cannot listen for SIGTERM");
+        tokio::select! {
+            _ = term.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/* Check the input on every tick of `schedule` and run the upload if FM wrote a new export since
+ * the last successful one. A failed run is logged and retried on the next tick. A shutdown signal
+ * is only acted upon between runs so an upload is never cut off halfway.
+ */
+async fn run_daemon(cli: &CLIArguments, matches: &ArgMatches, schedule: &str, status_file: &Path) {
+    let schedule = cron::Schedule::from_str(schedule).expect("Invalid --schedule cron expression");
+    if cli.from_clipboard_text {
+        panic!("The daemon needs an input file to watch, not the clipboard");
+    }
+
+    let mut status = DaemonStatus {
+        pid: std::process::id(),
+        ..Default::default()
+    };
+    let mut uploaded: Option<SystemTime> = None;
+    let mut shutdown = std::pin::pin!(shutdown_signal());
+    log(&format!("watching {}", cli.input));
+
+    for next in schedule.upcoming(Utc) {
+        status.next_check = Some(timestamp(next));
+        atomic_write(status_file, &serde_json::to_vec_pretty(&status).unwrap())
+            .expect("Cannot write the daemon status file");
+
+        let wait = (next - Utc::now()).to_std().unwrap_or_default();
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = &mut shutdown => {
+                log("shutting down");
+                break;
+            }
+        }
+        status.last_check = Some(timestamp(Utc::now()));
+
+        let modified = match std::fs::metadata(&cli.input).and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(e) => {
+                log(&format!("cannot read {}: {}", cli.input, e));
+                status.last_error = Some(e.to_string());
+                continue;
+            }
+        };
+        if uploaded == Some(modified) {
+            continue;
+        }
+
+        log("new export found, uploading");
+        /* Runs panic on errors, in a task of its own that only ends this run */
+        let result = tokio::spawn(run(cli.clone(), matches.clone()))
+            .await
+            .map_err(|e| format!("Run failed: {}", e));
+        match result {
+            Ok(()) => {
+                log("upload finished");
+                uploaded = Some(modified);
+                status.last_upload = status.last_check.clone();
+                status.input_modified = Some(timestamp(modified.into()));
+                status.last_error = None;
+            }
+            Err(e) => {
+                log(&format!("upload failed, retrying on the next check: {}", e));
+                status.last_error = Some(e);
+            }
+        }
+    }
+}
+
+/* What --summary-file gets, for scripts that want to know how a run went */
+#[derive(Serialize)]
+struct RunSummary<'a> {
+    command: &'static str,
+    spreadsheet: &'a str,
+    /// None for snapshot, which reads the spreadsheet only
+    input: Option<&'a str>,
+    finished_at: String,
+    duration_ms: u64,
+    /// Player rows in the input, or for snapshot in the Squad sheet
+    input_rows: usize,
+    upload: Option<UploadReport>,
+    /// Players prune removed, or would remove with --dry-run
+    pruned: Vec<String>,
+    dropped_passthrough: Vec<String>,
+    api_usage: &'a ApiUsage,
+}
+
+impl<'a> RunSummary<'a> {
+    fn new(
+        cli: &'a CLIArguments,
+        input_rows: usize,
+        command: &'static str,
+        run_start: Instant,
+        usage: &'a ApiUsage,
+    ) -> Self {
+        RunSummary {
+            command,
+            spreadsheet: &cli.spreadsheet,
+            input: Some(if cli.from_clipboard_text { "clipboard" } else { &cli.input }),
+            finished_at: timestamp(Utc::now()),
+            duration_ms: run_start.elapsed().as_millis() as u64,
+            input_rows,
+            upload: None,
+            pruned: vec![],
+            dropped_passthrough: vec![],
+            api_usage: usage,
+        }
+    }
+
+    fn write(&self, path: &Path) {
+        atomic_write(path, &serde_json::to_vec_pretty(self).unwrap())
+            .expect("Cannot write the run summary");
+    }
+}
+
+/* What the pre-upload hook gets on stdin */
+#[derive(Serialize)]
+struct PreUploadPayload<'a> {
+    spreadsheet: &'a str,
+    first_row: u32,
+    headers: &'a [String],
+    rows: &'a [Vec<String>],
+}
+
+/* Run a user supplied shell command with `payload` as JSON on stdin. Hooks are only taken from
+ * the command line, never from the Settings tab: everybody with edit access to the spreadsheet
+ * could otherwise run commands on this machine. Returns whether the hook succeeded.
+ */
+async fn run_hook(name: &str, command: &str, payload: &impl Serialize) -> bool {
+    use tokio::io::AsyncWriteExt;
+
+    let mut shell = if cfg!(windows) {
+        let mut shell = tokio::process::Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = tokio::process::Command::new("sh");
+        shell.arg("-c");
+        shell
     };
+    let mut child = shell
+        .arg(command)
+        .stdin(std::process::Stdio::piped())
+        .spawn()
+        .unwrap_or_else(|e| panic!("Cannot start the {} hook: {}", name, e));
 
-    /* And now send the update request... */
-    let update = s
-        .values_update(
+    let json = serde_json::to_vec(payload).unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    /* A hook that doesn't care about its input may exit without reading it */
+    if let Err(e) = stdin.write_all(&json).await {
+        if e.kind() != std::io::ErrorKind::BrokenPipe {
+            panic!("Cannot pass data to the {} hook: {}", name, e);
+        }
+    }
+    drop(stdin);
+
+    let status = child
+        .wait()
+        .await
+        .unwrap_or_else(|e| panic!("Cannot wait for the {} hook: {}", name, e));
+    if !status.success() {
+        println!("The {} hook failed ({})", name, status);
+    }
+    status.success()
+}
+
+/* The actual run against the spreadsheet: upload, or prune for the prune command */
+async fn sync_spreadsheet(
+    s: sheets::spreadsheets::Spreadsheets,
+    mut cli: CLIArguments,
+    matches: &ArgMatches,
+    mut profile: RunProfile,
+) {
+    let run_start = Instant::now();
+    let mut usage = ApiUsage::default();
+    let timeout = cli.timeout;
+
+    /* A summary of an earlier run must not pass for this one if it fails */
+    if let Some(path) = &cli.summary_file {
+        if let Err(e) = std::fs::remove_file(path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                panic!("Cannot remove old run summary {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /* Spreadsheet metadata */
+    let stage_start = Instant::now();
+    let sc = deadline(timeout, s.get(&cli.spreadsheet, false, &[]))
+        .await
+        .unwrap();
+    usage.record_read(&sc.body);
+    println!("Connected to spreadsheet {}", sc.body.spreadsheet_id);
+    if squad_tab(&sc.body).is_some_and(|tab| tab.basic_filter.is_some()) {
+        println!("Warning: {}", FILTER_WARNING);
+    }
+
+    let settings = deadline(timeout, read_settings(&s, &sc.body, &mut usage))
+        .await
+        .expect("Failed to read the Settings tab");
+    let unknown = apply_settings(&mut cli, matches, &settings).unwrap_or_else(|e| panic!("{}", e));
+    for key in unknown {
+        println!("Ignoring unknown setting '{}' in the {} tab", key, SETTINGS_SHEET);
+    }
+    profile.record("spreadsheet metadata", stage_start, None);
+
+    if let Some(Command::Snapshot { out }) = &cli.command {
+        let download = SquadSnapshot::download(
+            &s,
             &cli.spreadsheet,
-            &new_range,
-            false,
-            DateTimeRenderOption::FormattedString,
-            ValueRenderOption::FormattedValue,
-            ValueInputOption::UserEntered,
-            &update_body,
+            cli.data_start_row as usize,
+            &mut usage,
+        );
+        let snapshot = deadline(timeout, download)
+            .await
+            .expect("Failed to download the Squad sheet");
+        snapshot.save(out).expect("Failed to store the snapshot");
+        let rows = snapshot.data_rows().len();
+        println!("Stored {} player rows in {}", rows, out.display());
+        usage.print_summary();
+
+        if let Some(path) = &cli.summary_file {
+            RunSummary {
+                input: None,
+                ..RunSummary::new(&cli, rows, "snapshot", run_start, &usage)
+            }
+            .write(path);
+        }
+        return;
+    }
+
+    /* Read our table from the input HTML file or the clipboard */
+    let stage_start = Instant::now();
+    let table = if cli.from_clipboard_text {
+        read_clipboard_table()
+    } else {
+        read_table(&cli.input)
+    };
+    println!("Got table with {} rows", table.rows.len());
+    profile.record("parse input", stage_start, Some((table.rows.len() as f64, "rows")));
+
+    let layout_file = PathBuf::from(format!("layout-{}.json", cli.spreadsheet));
+    let new_layout = check_layout(&table.headers, &layout_file, cli.accept_layout_change);
+
+    let stage_start = Instant::now();
+    let sheet_locale = sc
+        .body
+        .properties
+        .as_ref()
+        .map(|p| p.locale.as_str())
+        .unwrap_or_default();
+    let sheet_decimals = DecimalSeparator::for_locale(sheet_locale);
+    let matrix = massage_table(&table.rows, cli.decimal_separator, sheet_decimals, cli.decimals);
+    profile.record("prepare rows", stage_start, Some((matrix.len() as f64, "rows")));
+
+    if let Some(Command::Prune {
+        dry_run,
+        archive_to,
+    }) = &cli.command
+    {
+        let key = player_key(&table, &cli.key_headers);
+        let current: Vec<String> = matrix.iter().filter_map(|row| key.key(row)).collect();
+        let departed = prune_departed(
+            &s,
+            &sc.body,
+            cli.data_start_row as usize,
+            &key,
+            &current,
+            *dry_run,
+            archive_to.as_deref(),
+            &mut usage,
         )
+        .await
+        .expect("Failed to prune departed players");
+
+        if departed.is_empty() {
+            println!("No departed players found");
+        } else if *dry_run {
+            println!("Would remove: {}", departed.join(", "));
+        } else {
+            println!("Removed: {}", departed.join(", "));
+        }
+        usage.print_summary();
+
+        if let Some(path) = &cli.summary_file {
+            RunSummary {
+                pruned: departed,
+                ..RunSummary::new(&cli, table.rows.len(), "prune", run_start, &usage)
+            }
+            .write(path);
+        }
+        return;
+    }
+
+    /* Remember the user's own columns before the upload shuffles rows around */
+    let passthrough = match &cli.passthrough_columns {
+        Some(columns) => {
+            let key = player_key(&table, &cli.key_headers);
+            let read = Passthrough::read(
+                &s,
+                &cli.spreadsheet,
+                cli.data_start_row as usize,
+                &key,
+                columns,
+                &mut usage,
+            );
+            let passthrough = deadline(timeout, read)
+                .await
+                .expect("Failed to read pass-through columns");
+            let players: Vec<Option<String>> = matrix.iter().map(|row| key.key(row)).collect();
+            Some((passthrough, players))
+        }
+        None => None,
+    };
+
+    if let Some(cell) = &cli.metadata_cell {
+        let read = RunMetadata::read(&s, &cli.spreadsheet, cell, &mut usage);
+        let previous = deadline(timeout, read)
+            .await
+            .expect("Failed to read run metadata");
+        if let Some(previous) = previous {
+            println!(
+                "Previous upload: {} by version {} (source {})",
+                previous.uploaded_at, previous.version, previous.source_digest
+            );
+
+            /* Refuse to roll the sheet back to an older export */
+            let stale = table.modified.is_some_and(|m| previous.source_newer_than(m));
+            if previous.source_digest == metadata::source_digest(&table.source) {
+                println!("This export was uploaded before, uploading it again");
+            } else if stale && !cli.accept_stale_input {
+                panic!(
+                    "The input was written before the export uploaded last ({}), the sheet may \
+                     hold fresher data. Check the export and re-run with --accept-stale-input to \
+                     upload it anyway.",
+                    previous.source_modified.unwrap_or_default()
+                );
+            }
+        }
+    }
+
+    /* Last thing before the upload, so the hook only sees runs that will really upload */
+    if let Some(hook) = &cli.pre_upload_hook {
+        let payload = PreUploadPayload {
+            spreadsheet: &cli.spreadsheet,
+            first_row: cli.data_start_row,
+            headers: &table.headers,
+            rows: &matrix,
+        };
+        if !run_hook("pre-upload", hook, &payload).await {
+            panic!("Upload cancelled by the pre-upload hook");
+        }
+    }
+
+    let formats = cli.format_sheet.then(|| number_formats(&matrix, sheet_decimals.as_char()));
+    let rows = matrix.len();
+
+    let stage_start = Instant::now();
+    let bytes_before = usage.bytes_sent;
+
+    let uploader = DataUploader::new(&s, &cli.spreadsheet, cli.data_start_row as usize)
+        .staged(cli.staged || timeout.is_some())
+        .chunk_rows(cli.chunk_rows)
+        .clear_mode(cli.clear_mode);
+    let report = deadline(timeout, uploader.upload(matrix, &mut usage))
         .await
         .expect("Failed to upload new data");
-    println!("Updated data: {}", update.status);
+    let megabytes = (usage.bytes_sent - bytes_before) as f64 / 1_000_000.0;
+    profile.record("upload", stage_start, Some((megabytes, "MB")));
 
-    println!(
-        "Program finished in {} ms",
-        start_time.elapsed().as_millis()
-    );
+    report.print_summary();
+
+    if let Some(formats) = formats {
+        let format = format_squad_sheet(
+            &s,
+            &sc.body,
+            cli.data_start_row as usize,
+            rows,
+            &formats,
+            &mut usage,
+        );
+        deadline(timeout, format)
+            .await
+            .expect("Failed to format the Squad sheet");
+        println!("Formatted Squad sheet");
+    }
+
+    let mut dropped_passthrough = vec![];
+    if let Some((passthrough, players)) = passthrough {
+        let write_back = passthrough.write_back(&s, &cli.spreadsheet, &players, &mut usage);
+        let dropped = deadline(timeout, write_back)
+            .await
+            .expect("Failed to restore pass-through columns");
+        println!("Restored pass-through columns");
+        if !dropped.is_empty() {
+            println!(
+                "Dropped pass-through values of players no longer in the squad: {}",
+                dropped.join(", ")
+            );
+        }
+        dropped_passthrough = dropped;
+    }
+
+    if let Some(cell) = &cli.metadata_cell {
+        let metadata = RunMetadata::new(&table.source, table.modified);
+        let write = metadata.write(&s, &cli.spreadsheet, cell, &mut usage);
+        deadline(timeout, write)
+            .await
+            .expect("Failed to write run metadata");
+    }
+
+    if let Some(layout) = new_layout {
+        layout.save(&layout_file).expect("Cannot store layout fingerprint");
+    }
+    usage.print_summary();
+    profile.print();
+
+    let summary = RunSummary {
+        upload: Some(report),
+        dropped_passthrough,
+        ..RunSummary::new(&cli, table.rows.len(), "upload", run_start, &usage)
+    };
+    if let Some(path) = &cli.summary_file {
+        summary.write(path);
+    }
+    if let Some(hook) = &cli.post_upload_hook {
+        /* The upload went through, a failing notification doesn't change that */
+        run_hook("post-upload", hook, &summary).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(cells: &[&str]) -> Vec<String> {
+        cells.iter().map(|c| c.to_string()).collect()
+    }
+
+    fn table(cells: &[&str]) -> Vec<Vec<String>> {
+        vec![row(cells)]
+    }
+
+    #[test]
+    fn round_decimal_rounds_half_away_from_zero() {
+        assert_eq!(round_decimal("2", "675", 2), ("2".to_string(), "68".to_string()));
+        assert_eq!(round_decimal("2", "674", 2), ("2".to_string(), "67".to_string()));
+        assert_eq!(round_decimal("2", "5", 2), ("2".to_string(), "5".to_string()));
+    }
+
+    #[test]
+    fn round_decimal_carries_into_the_integer_part() {
+        assert_eq!(round_decimal("9", "96", 1), ("10".to_string(), "0".to_string()));
+        assert_eq!(round_decimal("99", "5", 0), ("100".to_string(), "".to_string()));
+    }
+
+    #[test]
+    fn convert_decimal_switches_separator_and_rounds() {
+        use DecimalSeparator::{Comma, Dot};
+        assert_eq!(convert_decimal("12,5", Comma, Dot, None).as_deref(), Some("12.5"));
+        assert_eq!(convert_decimal("6,87", Comma, Dot, Some(1)).as_deref(), Some("6.9"));
+        assert_eq!(convert_decimal("9.96", Dot, Dot, Some(1)).as_deref(), Some("10.0"));
+        assert_eq!(convert_decimal("-1,25", Comma, Dot, Some(1)).as_deref(), Some("-1.3"));
+        assert_eq!(convert_decimal("12", Comma, Dot, None), None);
+        assert_eq!(convert_decimal("Left", Comma, Dot, None), None);
+        assert_eq!(convert_decimal("1,5,0", Comma, Dot, None), None);
+    }
+
+    #[test]
+    fn convert_decimal_drops_the_sign_of_zero() {
+        use DecimalSeparator::{Comma, Dot};
+        assert_eq!(convert_decimal("-0,04", Comma, Dot, Some(1)).as_deref(), Some("0.0"));
+        assert_eq!(convert_decimal("-0,4", Comma, Dot, Some(0)).as_deref(), Some("0"));
+        assert_eq!(convert_decimal("-0,05", Comma, Dot, Some(1)).as_deref(), Some("-0.1"));
+    }
+
+    #[test]
+    fn detect_decimal_separator_from_the_data() {
+        use DecimalSeparator::{Comma, Dot};
+        assert_eq!(detect_decimal_separator(&table(&["Name", "12,5", "3"])), Comma);
+        assert_eq!(detect_decimal_separator(&table(&["Name", "12.5", "3"])), Dot);
+        assert_eq!(detect_decimal_separator(&table(&["12,5", "12.5"])), Dot);
+        assert_eq!(detect_decimal_separator(&table(&["Name", "3"])), Dot);
+    }
+
+    #[test]
+    fn detect_decimal_separator_ignores_thousands_separators() {
+        use DecimalSeparator::{Comma, Dot};
+        assert_eq!(detect_decimal_separator(&table(&["1,500", "-12,000,000"])), Dot);
+        assert_eq!(detect_decimal_separator(&table(&["1,500", "6,87"])), Comma);
+        assert_eq!(detect_decimal_separator(&table(&["1.234", "6,87"])), Comma);
+        assert_eq!(detect_decimal_separator(&table(&["1.234", "6.87"])), Dot);
+    }
+
+    #[test]
+    fn convert_decimal_drops_thousands_separators() {
+        use DecimalSeparator::{Comma, Dot};
+        assert_eq!(convert_decimal("1.234", Comma, Dot, None).as_deref(), Some("1234"));
+        assert_eq!(convert_decimal("-1.234.567", Comma, Dot, None).as_deref(), Some("-1234567"));
+        assert_eq!(convert_decimal("1,500", Dot, Comma, None).as_deref(), Some("1500"));
+        assert_eq!(convert_decimal("1.234", Dot, Comma, None).as_deref(), Some("1,234"));
+        assert_eq!(convert_decimal("1.2345", Comma, Dot, None), None);
+    }
+
+    #[test]
+    fn massage_table_with_thousands_and_decimals() {
+        use DecimalSeparator::{Auto, Dot};
+        let input = table(&["1.234", "6,87", "Left"]);
+        assert_eq!(massage_table(&input, Auto, Dot, None), table(&["1234", "6.87", "l"]));
+    }
+
+    #[cfg(feature = "clipboard")]
+    #[test]
+    fn tab_separated_with_header() {
+        let input = parse_tab_separated("Name\tAge\tCA\n\nSilva \t23\t140\nMoura\t31\t152\n");
+        assert_eq!(input.headers, ["Name", "Age", "CA"]);
+        assert_eq!(input.rows, vec![row(&["Silva", "23", "140"]), row(&["Moura", "31", "152"])]);
+    }
+
+    #[cfg(feature = "clipboard")]
+    #[test]
+    fn tab_separated_without_header() {
+        let input = parse_tab_separated("Silva\t23\t140\r\nMoura\t31\t152");
+        assert!(input.headers.is_empty());
+        assert_eq!(input.rows.len(), 2);
+    }
+
+    #[cfg(feature = "clipboard")]
+    #[test]
+    #[should_panic(expected = "Clipboard line 3 has 2 columns, expected 3")]
+    fn tab_separated_rows_must_be_complete() {
+        parse_tab_separated("Name\tAge\tCA\nSilva\t23\t140\nMoura\t31");
+    }
+
+    #[cfg(feature = "clipboard")]
+    #[test]
+    #[should_panic(expected = "Clipboard text contains no data")]
+    fn tab_separated_needs_data() {
+        parse_tab_separated("\n  \n");
+    }
+
+    #[test]
+    fn settings_are_validated() {
+        let matches = CLIArguments::command().get_matches_from(["fm_google_up", "--decimals", "2"]);
+        let mut cli = CLIArguments::from_arg_matches(&matches).unwrap();
+        let settings = |pairs: &[(&str, &str)]| -> BTreeMap<String, String> {
+            pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+        };
+
+        let ok = settings(&[("data_start_row", "3"), ("decimals", "1"), ("colour", "red")]);
+        assert_eq!(apply_settings(&mut cli, &matches, &ok), Ok(vec!["colour"]));
+        assert_eq!(cli.data_start_row, 3);
+        assert_eq!(cli.decimals, Some(2));
+
+        for bad in [("data_start_row", "0"), ("decimal_separator", "foo")] {
+            assert!(apply_settings(&mut cli, &matches, &settings(&[bad])).is_err());
+        }
+    }
+
+    #[test]
+    fn decimal_separator_for_locale() {
+        use DecimalSeparator::{Comma, Dot};
+        assert_eq!(DecimalSeparator::for_locale("de_DE"), Comma);
+        assert_eq!(DecimalSeparator::for_locale("es_ES"), Comma);
+        assert_eq!(DecimalSeparator::for_locale("en_US"), Dot);
+        assert_eq!(DecimalSeparator::for_locale("es_MX"), Dot);
+        assert_eq!(DecimalSeparator::for_locale("de-CH"), Dot);
+        assert_eq!(DecimalSeparator::for_locale("fr_CH"), Comma);
+    }
 }
@@ -0,0 +1,147 @@
+//! Optional cosmetics after an upload: frozen header rows, fitted column widths and number
+//! formats, so a fresh tab is readable right away.
+
+use crate::batch::{batch_update, grid_range, request, sheet_id};
+use crate::upload::COLUMNS;
+use crate::usage::ApiUsage;
+use anyhow::anyhow;
+use serde_json::json;
+use sheets::spreadsheets::Spreadsheets;
+use sheets::types::Spreadsheet;
+
+/// Number format pattern per column, derived from the uploaded values: "0" for whole numbers,
+/// "0.0", "0.00", ... for decimals and `None` for columns that hold any text. Cells are written
+/// the way the sheet's locale reads them: `decimal` (',' or '.') separates the fraction and the
+/// other one groups thousands.
+pub fn number_formats(matrix: &[Vec<String>], decimal: char) -> Vec<Option<String>> {
+    let width = matrix.iter().map(|row| row.len()).max().unwrap_or(0);
+    (0..width)
+        .map(|col| {
+            let mut decimals = 0;
+            let mut numbers = 0;
+            for cell in matrix.iter().filter_map(|row| row.get(col)) {
+                if cell.is_empty() {
+                    continue;
+                }
+                decimals = decimals.max(fraction_digits(cell, decimal)?);
+                numbers += 1;
+            }
+            match (numbers, decimals) {
+                (0, _) => None,
+                (_, 0) => Some("0".to_string()),
+                (_, d) => Some(format!("0.{}", "0".repeat(d))),
+            }
+        })
+        .collect()
+}
+
+/* Number of fractional digits of `cell` if it is a number like "-1,234.5" (decimal '.'), None
+ * for anything else. Thousands groups must have three digits, so "1,5" with decimal '.' is text.
+ */
+fn fraction_digits(cell: &str, decimal: char) -> Option<usize> {
+    let grouping = if decimal == ',' { '.' } else { ',' };
+    let unsigned = cell.strip_prefix('-').unwrap_or(cell);
+    let (int, frac) = unsigned.split_once(decimal).unwrap_or((unsigned, ""));
+    let digits = |s: &str| s.chars().all(|c| c.is_ascii_digit());
+
+    let groups: Vec<&str> = int.split(grouping).collect();
+    let grouped = groups[1..].iter().all(|g| g.len() == 3) && (1..=3).contains(&groups[0].len());
+    if int.is_empty()
+        || !groups.iter().all(|g| digits(g))
+        || (groups.len() > 1 && !grouped)
+        || !digits(frac)
+        || (unsigned.contains(decimal) && frac.is_empty())
+    {
+        return None;
+    }
+    Some(frac.len())
+}
+
+/// Freeze the rows above `first_row`, fit all column widths to their content and apply
+/// `formats` (see `number_formats`) to the `rows` uploaded player rows of the Squad tab.
+pub async fn format_squad_sheet(
+    sheets: &Spreadsheets,
+    spreadsheet: &Spreadsheet,
+    first_row: usize,
+    rows: usize,
+    formats: &[Option<String>],
+    usage: &mut ApiUsage,
+) -> anyhow::Result<()> {
+    let squad_id =
+        sheet_id(spreadsheet, "Squad").ok_or_else(|| anyhow!("Spreadsheet has no 'Squad' tab"))?;
+    let first = first_row - 1;
+
+    let mut requests = vec![
+        request(json!({"updateSheetProperties": {
+            "properties": {"sheetId": squad_id, "gridProperties": {"frozenRowCount": first}},
+            "fields": "gridProperties.frozenRowCount",
+        }})),
+        request(json!({"autoResizeDimensions": {"dimensions": {
+            "sheetId": squad_id,
+            "dimension": "COLUMNS",
+            "startIndex": 0,
+            "endIndex": COLUMNS,
+        }}})),
+    ];
+    for (col, pattern) in formats.iter().enumerate().take(COLUMNS) {
+        if rows == 0 {
+            break;
+        }
+        if let Some(pattern) = pattern {
+            requests.push(request(json!({"repeatCell": {
+                "range": grid_range(squad_id, first..first + rows, col..col + 1),
+                "cell": {"userEnteredFormat": {"numberFormat": {"type": "NUMBER", "pattern": pattern}}},
+                "fields": "userEnteredFormat.numberFormat",
+            }})));
+        }
+    }
+
+    batch_update(sheets, &spreadsheet.spreadsheet_id, requests, usage).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matrix(rows: &[&[&str]]) -> Vec<Vec<String>> {
+        rows.iter()
+            .map(|row| row.iter().map(|c| c.to_string()).collect())
+            .collect()
+    }
+
+    fn formats(patterns: &[Option<&str>]) -> Vec<Option<String>> {
+        patterns.iter().map(|p| p.map(String::from)).collect()
+    }
+
+    #[test]
+    fn text_columns_get_no_format() {
+        let m = matrix(&[&["Silva", "12", "inf"], &["Moura", "", "NaN"]]);
+        assert_eq!(number_formats(&m, '.'), formats(&[None, Some("0"), None]));
+        assert_eq!(number_formats(&matrix(&[&[""]]), '.'), formats(&[None]));
+    }
+
+    #[test]
+    fn most_decimals_decide() {
+        let m = matrix(&[&["6.9", "-1"], &["6.87", "3"], &["7", "-0"]]);
+        assert_eq!(number_formats(&m, '.'), formats(&[Some("0.00"), Some("0")]));
+        let m = matrix(&[&["6,9"], &["6,87"]]);
+        assert_eq!(number_formats(&m, ','), formats(&[Some("0.00")]));
+    }
+
+    #[test]
+    fn thousands_separators_are_not_decimals() {
+        let m = matrix(&[&["1,500", "1.500"], &["12", "2.5"]]);
+        assert_eq!(
+            number_formats(&m, '.'),
+            formats(&[Some("0"), Some("0.000")])
+        );
+        let m = matrix(&[&["1.500", "1,234.5", "1,5"]]);
+        assert_eq!(
+            number_formats(&m, ','),
+            formats(&[Some("0"), None, Some("0.0")])
+        );
+        let m = matrix(&[&["1,5"], &["12,34"]]);
+        assert_eq!(number_formats(&m, '.'), formats(&[None]));
+    }
+}
@@ -0,0 +1,125 @@
+//! Telling players apart across the input and the spreadsheet.
+//!
+//! By default a player is identified by name. Squads with two "J. Silva" can add more columns
+//! to the key, e.g. age or the FM unique ID if the view exports it.
+
+use anyhow::{anyhow, bail};
+use std::collections::HashMap;
+
+/// Which columns make up a player's identity. The first column is the name.
+#[derive(Debug, Clone)]
+pub struct PlayerKey {
+    columns: Vec<usize>,
+    headers: Vec<String>,
+}
+
+impl PlayerKey {
+    /// Build a key from header names, e.g. `["Name", "Age"]`, looked up in `headers`.
+    pub fn from_headers(headers: &[String], key_headers: &[String]) -> anyhow::Result<Self> {
+        if key_headers.is_empty() {
+            bail!("A player key needs at least one column");
+        }
+        let columns = key_headers
+            .iter()
+            .map(|wanted| {
+                headers
+                    .iter()
+                    .position(|h| h == wanted)
+                    .ok_or_else(|| anyhow!("Input has no '{}' column", wanted))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(PlayerKey {
+            columns,
+            headers: key_headers.to_vec(),
+        })
+    }
+
+    /// The key of `row`, `None` for rows without a name (empty rows, totals, ...).
+    pub fn key(&self, row: &[String]) -> Option<String> {
+        let name = row.get(self.columns[0])?;
+        if name.is_empty() {
+            return None;
+        }
+        let parts: Vec<&str> = self
+            .columns
+            .iter()
+            .map(|&col| row.get(col).map(String::as_str).unwrap_or_default())
+            .collect();
+        Some(parts.join(" | "))
+    }
+
+    /// Fail if two of `rows` share a key, naming the ambiguous players and how to fix it.
+    pub fn check_unique<'a>(
+        &self,
+        rows: impl IntoIterator<Item = &'a Vec<String>>,
+        source: &str,
+    ) -> anyhow::Result<()> {
+        let mut seen: HashMap<String, usize> = HashMap::new();
+        for key in rows.into_iter().filter_map(|row| self.key(row)) {
+            *seen.entry(key).or_default() += 1;
+        }
+        let mut ambiguous: Vec<String> = seen
+            .into_iter()
+            .filter(|(_, count)| *count > 1)
+            .map(|(key, count)| format!("'{}' ({} rows)", key, count))
+            .collect();
+        if ambiguous.is_empty() {
+            return Ok(());
+        }
+        ambiguous.sort();
+        bail!(
+            "Players are ambiguous in {} when identified by {}: {}. Add a column that tells them \
+             apart, e.g. --key-headers {},Age",
+            source,
+            self.headers.join("+"),
+            ambiguous.join(", "),
+            self.headers.join(",")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(cells: &[&str]) -> Vec<String> {
+        cells.iter().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn key_from_headers() {
+        let headers = row(&["Name", "Age", "UID"]);
+        let key = PlayerKey::from_headers(&headers, &row(&["Name", "UID"])).unwrap();
+        assert_eq!(
+            key.key(&row(&["J. Silva", "23", "42"])).as_deref(),
+            Some("J. Silva | 42")
+        );
+        assert_eq!(key.key(&row(&["", "23", "42"])), None);
+        assert_eq!(key.key(&row(&["J. Silva"])).as_deref(), Some("J. Silva | "));
+
+        assert!(PlayerKey::from_headers(&headers, &row(&["Club"])).is_err());
+        assert!(PlayerKey::from_headers(&headers, &[]).is_err());
+    }
+
+    #[test]
+    fn ambiguous_players() {
+        let headers = row(&["Name", "Age"]);
+        let rows = vec![
+            row(&["J. Silva", "23"]),
+            row(&["J. Silva", "31"]),
+            row(&["", "19"]),
+            row(&["", "20"]),
+        ];
+
+        let by_name = PlayerKey::from_headers(&headers, &row(&["Name"])).unwrap();
+        let error = by_name.check_unique(&rows, "the input").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Players are ambiguous in the input when identified by Name: 'J. Silva' (2 rows). \
+             Add a column that tells them apart, e.g. --key-headers Name,Age"
+        );
+
+        let by_name_and_age = PlayerKey::from_headers(&headers, &headers).unwrap();
+        assert!(by_name_and_age.check_unique(&rows, "the input").is_ok());
+    }
+}
@@ -0,0 +1,117 @@
+//! Fingerprints of the input column layout.
+//!
+//! The Squad sheet addresses columns by position, so a re-ordered or renamed column in the FM
+//! view silently shifts data into the wrong place. We remember the header row of the last
+//! successful upload and refuse to continue when it changes unless the user confirms.
+
+use crate::lock::atomic_write;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::Path;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct LayoutFingerprint {
+    pub headers: Vec<String>,
+    pub hash: u64,
+}
+
+/* FNV-1a, so the hash stays stable across Rust releases unlike DefaultHasher. */
+fn fnv1a(headers: &[String]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for (pos, header) in headers.iter().enumerate() {
+        for byte in format!("{}:{}\n", pos, header).bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+impl LayoutFingerprint {
+    pub fn new(headers: &[String]) -> Self {
+        LayoutFingerprint {
+            headers: headers.to_vec(),
+            hash: fnv1a(headers),
+        }
+    }
+
+    /// Load a stored fingerprint, `None` if there is none yet.
+    pub fn load(path: &Path) -> io::Result<Option<Self>> {
+        match std::fs::read(path) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        atomic_write(path, &json)
+    }
+
+    /// Human readable list of column changes from `self` to `other`, empty if they match.
+    pub fn diff(&self, other: &LayoutFingerprint) -> Vec<String> {
+        if self.hash == other.hash && self.headers == other.headers {
+            return vec![];
+        }
+
+        let mut changes = vec![];
+        let columns = self.headers.len().max(other.headers.len());
+        for col in 0..columns {
+            match (self.headers.get(col), other.headers.get(col)) {
+                (Some(old), Some(new)) if old != new => {
+                    changes.push(format!("column {}: '{}' -> '{}'", col + 1, old, new))
+                }
+                (Some(old), None) => changes.push(format!("column {}: '{}' removed", col + 1, old)),
+                (None, Some(new)) => changes.push(format!("column {}: '{}' added", col + 1, new)),
+                _ => {}
+            }
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(headers: &[&str]) -> LayoutFingerprint {
+        let headers: Vec<String> = headers.iter().map(|h| h.to_string()).collect();
+        LayoutFingerprint::new(&headers)
+    }
+
+    #[test]
+    fn same_layout_has_no_diff() {
+        let old = layout(&["Name", "Age", "Club"]);
+        assert!(old.diff(&layout(&["Name", "Age", "Club"])).is_empty());
+    }
+
+    #[test]
+    fn moved_and_renamed_columns() {
+        let old = layout(&["Name", "Age", "Club"]);
+        assert_eq!(
+            old.diff(&layout(&["Name", "Club", "Age"])),
+            vec!["column 2: 'Age' -> 'Club'", "column 3: 'Club' -> 'Age'"]
+        );
+        assert_eq!(
+            old.diff(&layout(&["Name", "Years", "Club"])),
+            vec!["column 2: 'Age' -> 'Years'"]
+        );
+    }
+
+    #[test]
+    fn added_and_removed_columns() {
+        let old = layout(&["Name", "Age"]);
+        assert_eq!(
+            old.diff(&layout(&["Name", "Age", "Club"])),
+            vec!["column 3: 'Club' added"]
+        );
+        assert_eq!(
+            old.diff(&layout(&["Name"])),
+            vec!["column 2: 'Age' removed"]
+        );
+    }
+}
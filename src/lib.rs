@@ -0,0 +1,28 @@
+//! Shared building blocks for the fm_data tools.
+//!
+//! Modules talking to Google need the `sheets` feature.
+
+#[cfg(feature = "sheets")]
+pub mod auth;
+#[cfg(feature = "sheets")]
+pub mod batch;
+#[cfg(feature = "sheets")]
+pub mod format;
+#[cfg(feature = "sheets")]
+pub mod identity;
+pub mod layout;
+pub mod lock;
+#[cfg(feature = "sheets")]
+pub mod metadata;
+#[cfg(feature = "sheets")]
+pub mod passthrough;
+pub mod profile;
+#[cfg(feature = "sheets")]
+pub mod prune;
+#[cfg(feature = "sheets")]
+pub mod settings;
+#[cfg(feature = "sheets")]
+pub mod snapshot;
+#[cfg(feature = "sheets")]
+pub mod upload;
+pub mod usage;
@@ -0,0 +1,136 @@
+//! Lock files and atomic writes for the local state files we keep on disk (token cache, ...).
+//!
+//! Several of our processes can run at the same time, e.g. a long-running watch and a manual
+//! upload. They coordinate through a `<file>.lock` next to the state file and only ever replace
+//! state files via rename, so a reader never sees a half-written file.
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How long we wait for another process to release a lock before giving up.
+pub const WAIT_FOR: Duration = Duration::from_secs(10);
+
+/// An exclusive lock held for as long as this value lives.
+///
+/// This is an advisory lock of the OS on the lock file, so it is released when the holder exits,
+/// crashed or not. The lock file itself stays behind for the next run: removing it would let a
+/// process waiting on the old file and one creating a new file both believe they hold the lock.
+#[derive(Debug)]
+pub struct FileLock {
+    _file: File,
+}
+
+impl FileLock {
+    /// Acquire the lock at `path` with the default wait time.
+    pub async fn acquire(path: impl Into<PathBuf>) -> io::Result<FileLock> {
+        FileLock::acquire_with(path, WAIT_FOR).await
+    }
+
+    pub async fn acquire_with(path: impl Into<PathBuf>, wait: Duration) -> io::Result<FileLock> {
+        let path = path.into();
+        let start = Instant::now();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        loop {
+            match file.try_lock() {
+                Ok(()) => {
+                    /* Record the owner so the user knows whom to blame when we time out. */
+                    file.set_len(0)?;
+                    file.rewind()?;
+                    writeln!(file, "{}", std::process::id())?;
+                    return Ok(FileLock { _file: file });
+                }
+                Err(TryLockError::WouldBlock) => {
+                    if start.elapsed() >= wait {
+                        let mut owner = String::new();
+                        let _ = file.read_to_string(&mut owner);
+                        return Err(io::Error::new(
+                            io::ErrorKind::WouldBlock,
+                            format!(
+                                "{} is held by process {} for more than {}s",
+                                path.display(),
+                                owner.trim(),
+                                wait.as_secs()
+                            ),
+                        ));
+                    }
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+                Err(TryLockError::Error(e)) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Path of the lock file guarding `path`.
+pub fn lock_path_for(path: &Path) -> PathBuf {
+    with_suffix(path, ".lock")
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Replace `path` with `contents` so that readers see either the old or the new file. The new
+/// file is only readable by the current user, as it may contain secrets.
+pub fn atomic_write(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp = with_suffix(path, &format!(".tmp{}", std::process::id()));
+
+    let result = (|| {
+        let mut opts = OpenOptions::new();
+        opts.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            opts.mode(0o600);
+        }
+        let mut file = opts.open(&tmp)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_is_exclusive_until_dropped() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("lock-test-{}.lock", std::process::id()));
+
+        runtime.block_on(async {
+            let first = FileLock::acquire_with(&path, Duration::ZERO).await.unwrap();
+
+            let error = FileLock::acquire_with(&path, Duration::from_millis(200))
+                .await
+                .unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+            let owner = format!("held by process {}", std::process::id());
+            assert!(error.to_string().contains(&owner), "{}", error);
+
+            drop(first);
+            FileLock::acquire_with(&path, Duration::ZERO).await.unwrap();
+        });
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,214 @@
+//! A small block of run metadata kept in the spreadsheet, so collaborators can tell when the
+//! data was last refreshed and from which export.
+//!
+//! The block is two columns wide (label, value) and four rows high, anchored at a cell chosen
+//! by the user, e.g. `Settings!D1`. Blocks written before the "Source modified" row existed
+//! are still read.
+
+use crate::passthrough::{column_index, column_letter};
+use crate::usage::ApiUsage;
+use anyhow::anyhow;
+use chrono::{DateTime, SecondsFormat, Utc};
+use sha2::{Digest, Sha256};
+use sheets::spreadsheets::Spreadsheets;
+use sheets::types::{
+    DateTimeRenderOption, Dimension, ValueInputOption, ValueRange, ValueRenderOption,
+};
+use std::time::SystemTime;
+
+const LABELS: [&str; 4] = [
+    "Last upload",
+    "Tool version",
+    "Source SHA-256",
+    "Source modified",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunMetadata {
+    /// RFC 3339 UTC timestamp
+    pub uploaded_at: String,
+    pub version: String,
+    pub source_digest: String,
+    /// RFC 3339 UTC modification time of the input file, if it came from one
+    pub source_modified: Option<String>,
+}
+
+/// Hex SHA-256 of the input the upload was made from.
+pub fn source_digest(source: &[u8]) -> String {
+    Sha256::digest(source)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn rfc3339(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/* "Settings!D1" -> "Settings!D1:E4" */
+fn block_range(anchor: &str) -> anyhow::Result<String> {
+    let invalid = || anyhow!("'{}' is not a single cell like Settings!D1", anchor);
+    let (tab, cell) = anchor.rsplit_once('!').ok_or_else(invalid)?;
+    let split = cell
+        .find(|c: char| c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let (letters, digits) = cell.split_at(split);
+    let col = column_index(letters).ok_or_else(invalid)?;
+    let row: usize = digits.parse().map_err(|_| invalid())?;
+    Ok(format!(
+        "{}!{}{}:{}{}",
+        tab,
+        column_letter(col),
+        row,
+        column_letter(col + 1),
+        row + LABELS.len() - 1
+    ))
+}
+
+impl RunMetadata {
+    /// Metadata for an upload happening now from `source`, last modified at `modified`.
+    pub fn new(source: &[u8], modified: Option<SystemTime>) -> Self {
+        RunMetadata {
+            uploaded_at: rfc3339(Utc::now()),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            source_digest: source_digest(source),
+            source_modified: modified.map(|m| rfc3339(m.into())),
+        }
+    }
+
+    /// Whether the input of this upload was modified after `modified`, i.e. an input modified at
+    /// `modified` is older. `false` if the time of this upload's input is unknown.
+    pub fn source_newer_than(&self, modified: SystemTime) -> bool {
+        let previous = self
+            .source_modified
+            .as_deref()
+            .and_then(|m| DateTime::parse_from_rfc3339(m).ok());
+        previous.is_some_and(|previous| {
+            previous.timestamp() > DateTime::<Utc>::from(modified).timestamp()
+        })
+    }
+
+    /// Read the block at `anchor`. `None` if it is empty or does not look like ours.
+    pub async fn read(
+        sheets: &Spreadsheets,
+        spreadsheet: &str,
+        anchor: &str,
+        usage: &mut ApiUsage,
+    ) -> anyhow::Result<Option<Self>> {
+        let range = block_range(anchor)?;
+        let values = sheets
+            .values_get(
+                spreadsheet,
+                &range,
+                DateTimeRenderOption::FormattedString,
+                Dimension::Rows,
+                ValueRenderOption::FormattedValue,
+            )
+            .await?;
+        usage.record_read(&values.body);
+
+        let rows = values.body.values;
+        let value = |i: usize| -> Option<String> {
+            let row = rows.get(i)?;
+            (row.first()? == LABELS[i]).then(|| row.get(1).cloned().unwrap_or_default())
+        };
+        Ok((|| {
+            Some(RunMetadata {
+                uploaded_at: value(0)?,
+                version: value(1)?,
+                source_digest: value(2)?,
+                source_modified: value(3).filter(|m| !m.is_empty()),
+            })
+        })())
+    }
+
+    /// Write the block at `anchor`, replacing what was there.
+    pub async fn write(
+        &self,
+        sheets: &Spreadsheets,
+        spreadsheet: &str,
+        anchor: &str,
+        usage: &mut ApiUsage,
+    ) -> anyhow::Result<()> {
+        let range = block_range(anchor)?;
+        let modified = self.source_modified.clone().unwrap_or_default();
+        let values = [
+            &self.uploaded_at,
+            &self.version,
+            &self.source_digest,
+            &modified,
+        ]
+        .iter()
+        .zip(LABELS)
+        .map(|(value, label)| vec![label.to_string(), value.to_string()])
+        .collect();
+        let body = ValueRange {
+            values,
+            major_dimension: Some(Dimension::Rows),
+            range: range.clone(),
+        };
+        /* RAW so Sheets keeps the timestamp as text instead of converting it into a date. */
+        let update = sheets
+            .values_update(
+                spreadsheet,
+                &range,
+                false,
+                DateTimeRenderOption::FormattedString,
+                ValueRenderOption::FormattedValue,
+                ValueInputOption::Raw,
+                &body,
+            )
+            .await?;
+        usage.record_write(&body, &update.body);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn block_below_the_anchor() {
+        assert_eq!(block_range("Settings!D1").unwrap(), "Settings!D1:E4");
+        assert_eq!(block_range("Settings!d1").unwrap(), "Settings!D1:E4");
+        assert_eq!(block_range("'My Tab'!AZ10").unwrap(), "'My Tab'!AZ10:BA13");
+        assert_eq!(block_range("'Notes!'!B2").unwrap(), "'Notes!'!B2:C5");
+    }
+
+    #[test]
+    fn anchor_must_be_one_cell() {
+        for anchor in [
+            "D1",
+            "Settings!D",
+            "Settings!1",
+            "Settings!D1x",
+            "Settings!D1:E4",
+        ] {
+            assert!(block_range(anchor).is_err(), "{}", anchor);
+        }
+    }
+
+    #[test]
+    fn compare_source_times() {
+        let at = |secs: u64| UNIX_EPOCH + Duration::from_secs(secs);
+        let previous = RunMetadata {
+            source_modified: Some(rfc3339(at(1_700_000_000).into())),
+            ..RunMetadata::new(b"export", None)
+        };
+        assert!(previous.source_newer_than(at(1_699_999_999)));
+        assert!(!previous.source_newer_than(at(1_700_000_000)));
+        /* The stored time has whole seconds, the same export read again must not be older */
+        assert!(!previous.source_newer_than(at(1_700_000_000) + Duration::from_millis(500)));
+        assert!(!previous.source_newer_than(at(1_700_000_001)));
+
+        let unknown = RunMetadata::new(b"clipboard", None);
+        assert!(!unknown.source_newer_than(at(0)));
+        let garbled = RunMetadata {
+            source_modified: Some("yesterday".to_string()),
+            ..unknown
+        };
+        assert!(!garbled.source_newer_than(at(0)));
+    }
+}
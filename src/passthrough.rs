@@ -0,0 +1,349 @@
+//! Keeping user maintained columns to the right of the uploaded data (notes, transfer status,
+//! ...) attached to the right player when the upload re-orders rows.
+
+use crate::identity::PlayerKey;
+use crate::upload::COLUMNS;
+use crate::usage::ApiUsage;
+use anyhow::{anyhow, bail};
+use sheets::spreadsheets::Spreadsheets;
+use sheets::types::{
+    DateTimeRenderOption, Dimension, ValueInputOption, ValueRange, ValueRenderOption,
+};
+use std::collections::HashMap;
+
+/// Spreadsheet column letters for a zero-based column index: 0 -> A, 26 -> AA.
+pub fn column_letter(mut index: usize) -> String {
+    let mut letters = vec![];
+    loop {
+        letters.push((b'A' + (index % 26) as u8) as char);
+        if index < 26 {
+            break;
+        }
+        index = index / 26 - 1;
+    }
+    letters.iter().rev().collect()
+}
+
+/// Zero-based column index for spreadsheet column letters: A -> 0, AA -> 26.
+pub fn column_index(letters: &str) -> Option<usize> {
+    if letters.is_empty() || !letters.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    let number = letters
+        .to_ascii_uppercase()
+        .bytes()
+        .fold(0, |acc, b| acc * 26 + (b - b'A' + 1) as usize);
+    Some(number - 1)
+}
+
+/// Cells of `range` (e.g. "Squad!A2:BD") as the user entered them: formulas as formulas,
+/// everything else as displayed, which a USER_ENTERED write parses back into the same value in
+/// the spreadsheet's locale. Rows run from the start of `range` to the last one holding data.
+///
+/// The values API doesn't work for this: with FORMULA rendering it returns numbers as JSON
+/// numbers, which the all-string `ValueRange` of the `sheets` crate fails to parse.
+pub async fn read_user_values(
+    sheets: &Spreadsheets,
+    spreadsheet: &str,
+    range: &str,
+    usage: &mut ApiUsage,
+) -> anyhow::Result<Vec<Vec<String>>> {
+    let response = sheets.get(spreadsheet, true, &[range.to_string()]).await?;
+    usage.record_read(&response.body);
+
+    let mut rows: Vec<Vec<String>> = response
+        .body
+        .sheets
+        .into_iter()
+        .flat_map(|sheet| sheet.data)
+        .flat_map(|data| data.row_data)
+        .map(|row| {
+            row.values
+                .into_iter()
+                .map(|cell| match cell.user_entered_value {
+                    Some(value) if !value.formula_value.is_empty() => value.formula_value,
+                    _ => cell.formatted_value,
+                })
+                .collect()
+        })
+        .collect();
+    while rows
+        .last()
+        .is_some_and(|row| row.iter().all(String::is_empty))
+    {
+        rows.pop();
+    }
+    Ok(rows)
+}
+
+/// `formula` as Sheets would write it when copying the cell `delta` rows down (up for negative
+/// `delta`): relative row numbers of cell references move along, `$` rows, text in quotes and
+/// sheet names stay as they are. A value write takes formulas literally, so without this a
+/// formula that moves with its player would still point at the player's old row.
+pub fn shift_rows(formula: &str, delta: i64) -> String {
+    if delta == 0 || !formula.starts_with('=') {
+        return formula.to_string();
+    }
+    let chars: Vec<char> = formula.chars().collect();
+    let mut shifted = String::with_capacity(formula.len());
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        /* Copy quoted text and 'sheet names' unchanged */
+        if c == '"' || c == '\'' {
+            let end = chars[i + 1..]
+                .iter()
+                .position(|&q| q == c)
+                .map_or(chars.len(), |p| i + 1 + p + 1);
+            shifted.extend(&chars[i..end]);
+            i = end;
+            continue;
+        }
+        if !(c.is_ascii_alphabetic() || c == '$') {
+            shifted.push(c);
+            i += 1;
+            continue;
+        }
+
+        /* A word: function or range name, or a reference like A5, $A5, A$5 */
+        let start = i;
+        while i < chars.len() && (chars[i].is_ascii_alphanumeric() || "$_.".contains(chars[i])) {
+            i += 1;
+        }
+        let word: String = chars[start..i].iter().collect();
+        let follows_word =
+            start > 0 && (chars[start - 1].is_ascii_alphanumeric() || chars[start - 1] == '_');
+        let is_call = chars.get(i) == Some(&'(');
+        match cell_reference(&word) {
+            Some((column, absolute, row)) if !follows_word && !is_call && !absolute => {
+                let row = (row as i64 + delta).max(1);
+                shifted.push_str(&format!("{}{}", column, row));
+            }
+            _ => shifted.push_str(&word),
+        }
+    }
+    shifted
+}
+
+/* "$AB12" -> Some(("$AB", false, 12)), "A$5" -> Some(("A", true, 5)), "LOG10" -> None */
+fn cell_reference(word: &str) -> Option<(&str, bool, usize)> {
+    let letters = word.strip_prefix('$').unwrap_or(word);
+    let digits_at = letters.find(|c: char| !c.is_ascii_alphabetic())?;
+    if digits_at == 0 || digits_at > 3 {
+        return None;
+    }
+    let (rest, absolute) = match letters[digits_at..].strip_prefix('$') {
+        Some(rest) => (rest, true),
+        None => (&letters[digits_at..], false),
+    };
+    if rest.is_empty() || !rest.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let column = &word[..word.len() - rest.len() - absolute as usize];
+    Some((column, absolute, rest.parse().ok()?))
+}
+
+/// The trailing columns as they were before the upload, keyed by player.
+pub struct Passthrough {
+    first_column: usize,
+    last_column: usize,
+    first_row: usize,
+    /// How many rows the data area had, all of which get rewritten
+    rows: usize,
+    /// Each player's sheet row before the upload and their trailing cells
+    by_player: HashMap<String, (usize, Vec<String>)>,
+}
+
+impl Passthrough {
+    /// Read the columns `columns` (e.g. "AY:BD") of the Squad sheet together with the player
+    /// `key` columns of the data area. Formulas are kept as formulas.
+    pub async fn read(
+        sheets: &Spreadsheets,
+        spreadsheet: &str,
+        first_row: usize,
+        key: &PlayerKey,
+        columns: &str,
+        usage: &mut ApiUsage,
+    ) -> anyhow::Result<Self> {
+        let (first, last) = columns
+            .split_once(':')
+            .and_then(|(a, b)| Some((column_index(a)?, column_index(b)?)))
+            .ok_or_else(|| anyhow!("'{}' is not a column range like AY:BD", columns))?;
+        if first < COLUMNS || last < first {
+            bail!(
+                "Pass-through columns {} must lie right of the data area (after {})",
+                columns,
+                column_letter(COLUMNS - 1)
+            );
+        }
+
+        let range = format!("Squad!A{}:{}", first_row, column_letter(last));
+        let rows = read_user_values(sheets, spreadsheet, &range, usage).await?;
+        let row_count = rows.len();
+        key.check_unique(&rows, "the Squad sheet")?;
+
+        let mut by_player = HashMap::new();
+        for (i, row) in rows.into_iter().enumerate() {
+            let Some(player) = key.key(&row) else {
+                continue;
+            };
+            let mut trailing: Vec<String> = row.into_iter().skip(first).collect();
+            trailing.resize(last - first + 1, String::new());
+            if trailing.iter().any(|v| !v.is_empty()) {
+                by_player.insert(player, (first_row + i, trailing));
+            }
+        }
+
+        Ok(Passthrough {
+            first_column: first,
+            last_column: last,
+            first_row,
+            rows: row_count,
+            by_player,
+        })
+    }
+
+    /// Write the saved columns back next to the rows of `players`, the player keys in upload
+    /// order. Formulas of players who moved get their row references shifted along. Returns the
+    /// keys of players whose saved values were dropped because they are no longer in the squad.
+    pub async fn write_back(
+        mut self,
+        sheets: &Spreadsheets,
+        spreadsheet: &str,
+        players: &[Option<String>],
+        usage: &mut ApiUsage,
+    ) -> anyhow::Result<Vec<String>> {
+        let width = self.last_column - self.first_column + 1;
+        let rows = players.len().max(self.rows);
+        if rows == 0 {
+            return Ok(vec![]);
+        }
+        let mut values = self.arrange(players);
+        /* Blank out whatever was below the new last row. */
+        values.resize(rows, vec![String::new(); width]);
+
+        let range = format!(
+            "Squad!{}{}:{}{}",
+            column_letter(self.first_column),
+            self.first_row,
+            column_letter(self.last_column),
+            self.first_row + rows - 1
+        );
+        let body = ValueRange {
+            values,
+            major_dimension: Some(Dimension::Rows),
+            range: range.clone(),
+        };
+        let update = sheets
+            .values_update(
+                spreadsheet,
+                &range,
+                false,
+                DateTimeRenderOption::FormattedString,
+                ValueRenderOption::FormattedValue,
+                ValueInputOption::UserEntered,
+                &body,
+            )
+            .await?;
+        usage.record_write(&body, &update.body);
+
+        let mut dropped: Vec<String> = self.by_player.into_keys().collect();
+        dropped.sort();
+        Ok(dropped)
+    }
+
+    /* The saved cells in the order of `players`, taking them out of `by_player` */
+    fn arrange(&mut self, players: &[Option<String>]) -> Vec<Vec<String>> {
+        let width = self.last_column - self.first_column + 1;
+        players
+            .iter()
+            .enumerate()
+            .map(|(i, player)| {
+                let Some((old_row, cells)) = player.as_ref().and_then(|p| self.by_player.remove(p))
+                else {
+                    return vec![String::new(); width];
+                };
+                let delta = (self.first_row + i) as i64 - old_row as i64;
+                cells.iter().map(|cell| shift_rows(cell, delta)).collect()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn column_letters() {
+        assert_eq!(column_letter(0), "A");
+        assert_eq!(column_letter(25), "Z");
+        assert_eq!(column_letter(26), "AA");
+        assert_eq!(column_letter(COLUMNS - 1), "AX");
+        assert_eq!(column_letter(COLUMNS), "AY");
+        assert_eq!(column_letter(701), "ZZ");
+        assert_eq!(column_letter(702), "AAA");
+    }
+
+    #[test]
+    fn column_indexes() {
+        assert_eq!(column_index("A"), Some(0));
+        assert_eq!(column_index("ax"), Some(49));
+        assert_eq!(column_index("AY"), Some(50));
+        assert_eq!(column_index(""), None);
+        assert_eq!(column_index("A1"), None);
+    }
+
+    #[test]
+    fn column_round_trip() {
+        for index in 0..1000 {
+            assert_eq!(column_index(&column_letter(index)), Some(index));
+        }
+    }
+
+    #[test]
+    fn shift_relative_rows() {
+        assert_eq!(shift_rows("=AVERAGE(E5:H5)", 2), "=AVERAGE(E7:H7)");
+        assert_eq!(shift_rows("=$E5+E$5+$E$5", -1), "=$E4+E$5+$E$5");
+        assert_eq!(shift_rows("=Squad!AB10*2", 3), "=Squad!AB13*2");
+        assert_eq!(shift_rows("=LOG10(A1)&\"A1\"", 1), "=LOG10(A2)&\"A1\"");
+        assert_eq!(shift_rows("='Notes A1'!B2", 1), "='Notes A1'!B3");
+        assert_eq!(shift_rows("=1E5+A1", 1), "=1E5+A2");
+    }
+
+    #[test]
+    fn shift_leaves_values_alone() {
+        assert_eq!(shift_rows("A5", 2), "A5");
+        assert_eq!(shift_rows("=E5", 0), "=E5");
+    }
+
+    #[test]
+    fn formulas_follow_their_player() {
+        let mut passthrough = Passthrough {
+            first_column: 50,
+            last_column: 51,
+            first_row: 2,
+            rows: 2,
+            by_player: HashMap::from([
+                (
+                    "A".to_string(),
+                    (2, vec!["=E2*2".to_string(), "keep".to_string()]),
+                ),
+                (
+                    "B".to_string(),
+                    (3, vec!["=AVERAGE(E3:H3)".to_string(), "".to_string()]),
+                ),
+            ]),
+        };
+        let players = [Some("B".to_string()), None, Some("A".to_string())];
+        assert_eq!(
+            passthrough.arrange(&players),
+            vec![
+                vec!["=AVERAGE(E2:H2)".to_string(), "".to_string()],
+                vec!["".to_string(), "".to_string()],
+                vec!["=E4*2".to_string(), "keep".to_string()],
+            ]
+        );
+        assert!(passthrough.by_player.is_empty());
+    }
+}
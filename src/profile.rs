@@ -0,0 +1,61 @@
+//! Opt-in wall time and throughput per pipeline stage, printed at the end of a run.
+
+use std::time::{Duration, Instant};
+
+struct Stage {
+    name: &'static str,
+    duration: Duration,
+    /// Amount of work done in this stage and its unit, e.g. (58.0, "rows").
+    work: Option<(f64, &'static str)>,
+}
+
+/// Collects stage timings. When disabled, recording is a no-op.
+pub struct RunProfile {
+    enabled: bool,
+    stages: Vec<Stage>,
+}
+
+impl RunProfile {
+    pub fn new(enabled: bool) -> Self {
+        RunProfile {
+            enabled,
+            stages: vec![],
+        }
+    }
+
+    /// Record a stage that began at `started` and ends now.
+    pub fn record(
+        &mut self,
+        name: &'static str,
+        started: Instant,
+        work: Option<(f64, &'static str)>,
+    ) {
+        if self.enabled {
+            self.stages.push(Stage {
+                name,
+                duration: started.elapsed(),
+                work,
+            });
+        }
+    }
+
+    pub fn print(&self) {
+        if !self.enabled {
+            return;
+        }
+        println!("Run profile:");
+        for stage in &self.stages {
+            let secs = stage.duration.as_secs_f64();
+            let throughput = match stage.work {
+                Some((amount, unit)) if secs > 0.0 => format!("{:.1} {}/s", amount / secs, unit),
+                _ => String::new(),
+            };
+            println!(
+                "  {:<20} {:>8} ms  {}",
+                stage.name,
+                stage.duration.as_millis(),
+                throughput
+            );
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! Removing players who left the club from the Squad sheet.
+
+use crate::batch::{batch_update, request, sheet_id};
+use crate::identity::PlayerKey;
+use crate::passthrough::read_user_values;
+use crate::usage::ApiUsage;
+use anyhow::anyhow;
+use serde_json::json;
+use sheets::spreadsheets::Spreadsheets;
+use sheets::types::{
+    DateTimeRenderOption, Dimension, InsertDataOption, Spreadsheet, ValueInputOption, ValueRange,
+    ValueRenderOption,
+};
+
+/// Find Squad rows whose player `key` is not in `current`, the keys of the latest export.
+/// Unless `dry_run` is set, the rows are appended to the `archive_to` tab (created if missing)
+/// and then deleted from the Squad sheet. Returns the departed players' keys.
+#[allow(clippy::too_many_arguments)]
+pub async fn prune_departed(
+    sheets: &Spreadsheets,
+    spreadsheet: &Spreadsheet,
+    first_row: usize,
+    key: &PlayerKey,
+    current: &[String],
+    dry_run: bool,
+    archive_to: Option<&str>,
+    usage: &mut ApiUsage,
+) -> anyhow::Result<Vec<String>> {
+    let id = &spreadsheet.spreadsheet_id;
+    let squad_id =
+        sheet_id(spreadsheet, "Squad").ok_or_else(|| anyhow!("Spreadsheet has no 'Squad' tab"))?;
+
+    let range = format!("Squad!A{}:AX", first_row);
+    let rows = read_user_values(sheets, id, &range, usage).await?;
+
+    /* (zero-based sheet row, player key, row values) of everybody who is gone */
+    let departed: Vec<(usize, String, Vec<String>)> = rows
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, row)| {
+            let player = key.key(&row)?;
+            (!current.contains(&player)).then_some((first_row - 1 + i, player, row))
+        })
+        .collect();
+    let names: Vec<String> = departed
+        .iter()
+        .map(|(_, player, _)| player.clone())
+        .collect();
+
+    if dry_run || departed.is_empty() {
+        return Ok(names);
+    }
+
+    if let Some(tab) = archive_to {
+        if sheet_id(spreadsheet, tab).is_none() {
+            let add = request(json!({"addSheet": {"properties": {"title": tab}}}));
+            batch_update(sheets, id, vec![add], usage).await?;
+        }
+        let archive_range = format!("{}!A1", tab);
+        let body = ValueRange {
+            values: departed.iter().map(|(_, _, row)| row.clone()).collect(),
+            major_dimension: Some(Dimension::Rows),
+            range: archive_range.clone(),
+        };
+        let appended = sheets
+            .values_append(
+                id,
+                &archive_range,
+                false,
+                InsertDataOption::InsertRows,
+                DateTimeRenderOption::FormattedString,
+                ValueRenderOption::FormattedValue,
+                ValueInputOption::UserEntered,
+                &body,
+            )
+            .await?;
+        usage.record_write(&body, &appended.body);
+    }
+
+    /* Delete bottom up so the indexes of the remaining rows stay valid. */
+    let deletes = departed
+        .iter()
+        .rev()
+        .map(|(row, _, _)| {
+            request(json!({"deleteDimension": {"range": {
+                "sheetId": squad_id,
+                "dimension": "ROWS",
+                "startIndex": row,
+                "endIndex": row + 1,
+            }}}))
+        })
+        .collect();
+    batch_update(sheets, id, deletes, usage).await?;
+
+    Ok(names)
+}
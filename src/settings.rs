@@ -0,0 +1,49 @@
+//! Optional settings stored in a "Settings" tab of the spreadsheet itself, so everybody working
+//! on a shared save uses the same configuration.
+//!
+//! The tab holds one `key | value` pair per row in columns A and B. Rows with an empty key or a
+//! key starting with `#` are ignored. Callers decide how the values rank against other sources;
+//! for fm_google_up, explicit command line arguments win over the Settings tab, which wins over
+//! built-in defaults.
+
+use crate::batch::sheet_id;
+use crate::usage::ApiUsage;
+use sheets::spreadsheets::Spreadsheets;
+use sheets::types::{DateTimeRenderOption, Dimension, Spreadsheet, ValueRenderOption};
+use std::collections::BTreeMap;
+
+pub const SETTINGS_SHEET: &str = "Settings";
+
+/// Read the Settings tab. Returns an empty map if the spreadsheet has none.
+pub async fn read_settings(
+    sheets: &Spreadsheets,
+    spreadsheet: &Spreadsheet,
+    usage: &mut ApiUsage,
+) -> Result<BTreeMap<String, String>, sheets::ClientError> {
+    if sheet_id(spreadsheet, SETTINGS_SHEET).is_none() {
+        return Ok(BTreeMap::new());
+    }
+
+    let range = format!("{}!A:B", SETTINGS_SHEET);
+    let values = sheets
+        .values_get(
+            &spreadsheet.spreadsheet_id,
+            &range,
+            DateTimeRenderOption::FormattedString,
+            Dimension::Rows,
+            ValueRenderOption::FormattedValue,
+        )
+        .await?;
+    usage.record_read(&values.body);
+
+    Ok(values
+        .body
+        .values
+        .into_iter()
+        .filter_map(|row| {
+            let key = row.first()?.trim().to_string();
+            let value = row.get(1).map(|v| v.trim().to_string()).unwrap_or_default();
+            (!key.is_empty() && !key.starts_with('#')).then_some((key, value))
+        })
+        .collect())
+}
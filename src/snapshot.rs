@@ -0,0 +1,120 @@
+//! A local copy of the Squad sheet, so tools reading it can run without network access and bug
+//! reports can ship the exact data they were made with.
+
+use crate::lock::atomic_write;
+use crate::usage::ApiUsage;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sheets::spreadsheets::Spreadsheets;
+use sheets::types::{DateTimeRenderOption, Dimension, ValueRenderOption};
+use std::path::Path;
+
+const SHEET: &str = "Squad";
+
+/// The Squad sheet's values (A:AX) as displayed, including the header rows above the data.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SquadSnapshot {
+    pub spreadsheet_id: String,
+    /// RFC 3339 UTC time of the download
+    pub taken_at: String,
+    /// 1-based row where player data starts, as configured for the upload
+    pub data_start_row: usize,
+    /// Row by row from row 1, trailing empty cells and rows omitted as the API returns them
+    pub rows: Vec<Vec<String>>,
+}
+
+impl SquadSnapshot {
+    /// Download the Squad sheet of `spreadsheet` in one request.
+    pub async fn download(
+        sheets: &Spreadsheets,
+        spreadsheet: &str,
+        data_start_row: usize,
+        usage: &mut ApiUsage,
+    ) -> anyhow::Result<Self> {
+        let values = sheets
+            .values_get(
+                spreadsheet,
+                &format!("{}!A:AX", SHEET),
+                DateTimeRenderOption::FormattedString,
+                Dimension::Rows,
+                ValueRenderOption::FormattedValue,
+            )
+            .await?;
+        usage.record_read(&values.body);
+
+        Ok(SquadSnapshot {
+            spreadsheet_id: spreadsheet.to_string(),
+            taken_at: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            data_start_row,
+            rows: values.body.values,
+        })
+    }
+
+    /// The player rows, without the header rows above them.
+    pub fn data_rows(&self) -> &[Vec<String>] {
+        let skip = self.data_start_row.saturating_sub(1).min(self.rows.len());
+        &self.rows[skip..]
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents =
+            std::fs::read(path).with_context(|| format!("Cannot read {}", path.display()))?;
+        serde_json::from_slice(&contents)
+            .with_context(|| format!("{} is not a Squad snapshot", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        atomic_write(path, &serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("Cannot write {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(data_start_row: usize, rows: &[&str]) -> SquadSnapshot {
+        SquadSnapshot {
+            spreadsheet_id: "sheet".to_string(),
+            taken_at: "2024-05-01T10:00:00Z".to_string(),
+            data_start_row,
+            rows: rows.iter().map(|name| vec![name.to_string()]).collect(),
+        }
+    }
+
+    #[test]
+    fn data_rows_skip_the_headers() {
+        let names = |s: &SquadSnapshot| -> Vec<String> {
+            s.data_rows().iter().map(|row| row[0].clone()).collect()
+        };
+        assert_eq!(names(&snapshot(2, &["Name", "A", "B"])), ["A", "B"]);
+        assert_eq!(names(&snapshot(1, &["A", "B"])), ["A", "B"]);
+        assert_eq!(names(&snapshot(0, &["A", "B"])), ["A", "B"]);
+        assert!(snapshot(5, &["Name", "A"]).data_rows().is_empty());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = std::env::temp_dir().join(format!("snapshot-test-{}.json", std::process::id()));
+        let saved = snapshot(2, &["Name", "A"]);
+        saved.save(&path).unwrap();
+        let loaded = SquadSnapshot::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.spreadsheet_id, saved.spreadsheet_id);
+        assert_eq!(loaded.taken_at, saved.taken_at);
+        assert_eq!(loaded.data_start_row, 2);
+        assert_eq!(loaded.rows, saved.rows);
+    }
+
+    #[test]
+    fn load_rejects_other_files() {
+        let path = std::env::temp_dir().join(format!("snapshot-bad-{}.json", std::process::id()));
+        std::fs::write(&path, "{\"rows\": []}").unwrap();
+        let error = SquadSnapshot::load(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(error.to_string().contains("is not a Squad snapshot"));
+
+        assert!(SquadSnapshot::load(Path::new("/nonexistent/snapshot.json")).is_err());
+    }
+}
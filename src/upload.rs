@@ -0,0 +1,474 @@
+//! Writing the squad table into the spreadsheet.
+
+use crate::batch::{batch_update, grid_range, request, sheet_id};
+use crate::passthrough::column_index;
+use crate::usage::ApiUsage;
+use anyhow::{anyhow, bail};
+use serde::{Serialize, Serializer};
+use serde_json::json;
+use sheets::spreadsheets::Spreadsheets;
+use sheets::types::{
+    ClearValuesRequest, DateTimeRenderOption, Dimension, Request, Spreadsheet, ValueInputOption,
+    ValueRange, ValueRenderOption,
+};
+use std::time::{Duration, Instant};
+
+/// Number of columns in the data area, A to AX.
+pub const COLUMNS: usize = 50;
+
+/// Tables taller than this are written in several requests of at most this many rows, keeping
+/// each request well below the API payload limits.
+pub const DEFAULT_CHUNK_ROWS: usize = 1000;
+
+const SHEET: &str = "Squad";
+const STAGING_SHEET: &str = "Squad_staging";
+
+/* ID and row count of the Squad tab */
+fn squad_tab(spreadsheet: &Spreadsheet) -> anyhow::Result<(i64, usize)> {
+    let id = sheet_id(spreadsheet, SHEET)
+        .ok_or_else(|| anyhow!("Spreadsheet has no '{}' tab", SHEET))?;
+    let rows = spreadsheet
+        .sheets
+        .iter()
+        .filter_map(|sheet| sheet.properties.as_ref())
+        .find(|props| props.sheet_id == id)
+        .and_then(|props| props.grid_properties.as_ref())
+        .map_or(0, |grid| grid.row_count as usize);
+    Ok((id, rows))
+}
+
+/* Number of cells in an A1 range like "Squad!A2:AX58" or "Squad!B3" */
+fn range_cells(range: &str) -> Option<usize> {
+    let cells = range.rsplit_once('!').map_or(range, |(_, cells)| cells);
+    let (start, end) = cells.split_once(':').unwrap_or((cells, cells));
+    let corner = |cell: &str| -> Option<(usize, usize)> {
+        let split = cell.find(|c: char| c.is_ascii_digit())?;
+        let (letters, digits) = cell.split_at(split);
+        Some((column_index(letters)?, digits.parse().ok()?))
+    };
+    let (first_col, first_row) = corner(start)?;
+    let (last_col, last_row) = corner(end)?;
+    Some((last_col + 1).checked_sub(first_col)? * (last_row + 1).checked_sub(first_row)?)
+}
+
+/// What the upload wipes from the old data area before writing.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ClearMode {
+    /// Only the values; formatting, notes and conditional formatting stay
+    #[default]
+    Values,
+    /// Values and cell notes
+    ValuesAndNotes,
+    /// Values, notes and cell formatting (colors, number formats, ...)
+    All,
+}
+
+impl ClearMode {
+    /* The `fields` mask of an updateCells request clearing this much */
+    fn fields(self) -> &'static str {
+        match self {
+            ClearMode::Values => "userEnteredValue",
+            ClearMode::ValuesAndNotes => "userEnteredValue,note",
+            ClearMode::All => "userEnteredValue,note,userEnteredFormat",
+        }
+    }
+}
+
+/// What an upload did, for the final summary and for programmatic checks.
+#[derive(Debug, Serialize)]
+pub struct UploadReport {
+    pub range: String,
+    pub rows_written: usize,
+    pub cells_written: usize,
+    pub cells_cleared: usize,
+    #[serde(rename = "duration_ms", serialize_with = "as_millis")]
+    pub duration: Duration,
+    pub warnings: Vec<String>,
+    /// The API confirmed it wrote exactly the rows and cells we sent.
+    pub verified: bool,
+}
+
+fn as_millis<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_millis() as u64)
+}
+
+impl UploadReport {
+    pub fn print_summary(&self) {
+        println!(
+            "Uploaded {} rows ({} cells) to {} in {} ms, cleared {} cells, verification {}",
+            self.rows_written,
+            self.cells_written,
+            self.range,
+            self.duration.as_millis(),
+            self.cells_cleared,
+            if self.verified { "passed" } else { "FAILED" }
+        );
+        for warning in &self.warnings {
+            println!("Warning: {}", warning);
+        }
+    }
+}
+
+/// Replaces the player rows of the Squad sheet with a new table.
+pub struct DataUploader<'a> {
+    sheets: &'a Spreadsheets,
+    spreadsheet: &'a str,
+    first_row: usize,
+    staged: bool,
+    chunk_rows: usize,
+    clear_mode: ClearMode,
+}
+
+impl<'a> DataUploader<'a> {
+    /// `first_row` is the 1-based spreadsheet row where player data starts.
+    pub fn new(sheets: &'a Spreadsheets, spreadsheet: &'a str, first_row: usize) -> Self {
+        DataUploader {
+            sheets,
+            spreadsheet,
+            first_row,
+            staged: false,
+            chunk_rows: DEFAULT_CHUNK_ROWS,
+            clear_mode: ClearMode::default(),
+        }
+    }
+
+    /// What to wipe from the old data area, values only by default.
+    pub fn clear_mode(mut self, clear_mode: ClearMode) -> Self {
+        self.clear_mode = clear_mode;
+        self
+    }
+
+    /// Maximum number of rows sent per write request.
+    pub fn chunk_rows(mut self, chunk_rows: usize) -> Self {
+        self.chunk_rows = chunk_rows.max(1);
+        self
+    }
+
+    /// Upload into a hidden staging tab first and only replace the Squad data once that
+    /// succeeded, so a failure never leaves the Squad sheet half-updated.
+    pub fn staged(mut self, staged: bool) -> Self {
+        self.staged = staged;
+        self
+    }
+
+    pub async fn upload(
+        &self,
+        matrix: Vec<Vec<String>>,
+        usage: &mut ApiUsage,
+    ) -> anyhow::Result<UploadReport> {
+        let start_time = Instant::now();
+        let mut warnings = vec![];
+
+        if let Some(widest) = matrix.iter().map(|row| row.len()).max() {
+            if widest > COLUMNS {
+                warnings.push(format!(
+                    "input has {} columns, only the first {} fit into the Squad sheet and the \
+                     rest were dropped",
+                    widest, COLUMNS
+                ));
+            }
+        }
+        /* The API rejects values outside the A:AX range we write to */
+        let matrix: Vec<Vec<String>> = matrix
+            .into_iter()
+            .map(|mut row| {
+                row.truncate(COLUMNS);
+                row
+            })
+            .collect();
+        let rows = matrix.len();
+        let cells: usize = matrix.iter().map(|row| row.len()).sum();
+        if rows > self.chunk_rows {
+            warnings.push(format!(
+                "{} rows are more than {} per request, uploaded in {} chunks",
+                rows,
+                self.chunk_rows,
+                rows.div_ceil(self.chunk_rows)
+            ));
+        }
+
+        let range = format!(
+            "{}!A{}:AX{}",
+            SHEET,
+            self.first_row,
+            self.first_row + rows - 1
+        );
+        let (updated_rows, updated_cells, cleared) = if self.staged && rows > 0 {
+            self.upload_staged(matrix, usage).await?
+        } else {
+            self.upload_direct(matrix, usage).await?
+        };
+
+        let verified = updated_rows == rows && updated_cells == cells;
+        if !verified {
+            warnings.push(format!(
+                "sent {} rows/{} cells but the API reports {} rows/{} cells written",
+                rows, cells, updated_rows, updated_cells
+            ));
+        }
+
+        Ok(UploadReport {
+            range,
+            rows_written: rows,
+            cells_written: cells,
+            cells_cleared: cleared,
+            duration: start_time.elapsed(),
+            warnings,
+            verified,
+        })
+    }
+
+    /* Write `matrix` into `sheet` starting at row `first_row`, in chunks of `chunk_rows` rows.
+     * Returns the number of rows and cells the API wrote.
+     */
+    async fn write_values(
+        &self,
+        matrix: Vec<Vec<String>>,
+        sheet: &str,
+        first_row: usize,
+        usage: &mut ApiUsage,
+    ) -> anyhow::Result<(usize, usize)> {
+        let mut written = (0, 0);
+        let mut matrix = matrix.into_iter().peekable();
+        let mut row = first_row;
+
+        while matrix.peek().is_some() {
+            let values: Vec<Vec<String>> = matrix.by_ref().take(self.chunk_rows).collect();
+            let range = format!("{}!A{}:AX{}", sheet, row, row + values.len() - 1);
+            row += values.len();
+
+            let update_body = ValueRange {
+                values,
+                major_dimension: Some(Dimension::Rows),
+                range: range.clone(),
+            };
+            let update = self
+                .sheets
+                .values_update(
+                    self.spreadsheet,
+                    &range,
+                    false,
+                    DateTimeRenderOption::FormattedString,
+                    ValueRenderOption::FormattedValue,
+                    ValueInputOption::UserEntered,
+                    &update_body,
+                )
+                .await?;
+            usage.record_write(&update_body, &update.body);
+            written.0 += update.body.updated_rows as usize;
+            written.1 += update.body.updated_cells as usize;
+        }
+        Ok(written)
+    }
+
+    /* Clear the old data, then write the new one. Returns the number of rows and cells written
+     * and of cells cleared.
+     */
+    async fn upload_direct(
+        &self,
+        matrix: Vec<Vec<String>>,
+        usage: &mut ApiUsage,
+    ) -> anyhow::Result<(usize, usize, usize)> {
+        let cleared = if self.clear_mode == ClearMode::Values {
+            /* Open ended, so everything down to the last row of the tab goes */
+            let clear_range = format!("{}!A{}:AX", SHEET, self.first_row);
+            let clear_body = ClearValuesRequest {};
+            let cleared = self
+                .sheets
+                .values_clear(self.spreadsheet, &clear_range, &clear_body)
+                .await?;
+            usage.record_write(&clear_body, &cleared.body);
+            let range = &cleared.body.cleared_range;
+            range_cells(range).unwrap_or_else(|| {
+                println!(
+                    "Warning: cannot count the cells of cleared range '{}'",
+                    range
+                );
+                0
+            })
+        } else {
+            /* values:clear only knows values, anything more needs updateCells and the tab ID */
+            let metadata = self.sheets.get(self.spreadsheet, false, &[]).await?;
+            usage.record_read(&metadata.body);
+            let (squad_id, squad_rows) = squad_tab(&metadata.body)?;
+            let (clear, cleared) = self.clear_request(squad_id, squad_rows);
+            batch_update(self.sheets, self.spreadsheet, vec![clear], usage).await?;
+            cleared
+        };
+        println!("Cleared old data");
+
+        if matrix.is_empty() {
+            return Ok((0, 0, cleared));
+        }
+        let (rows, cells) = self
+            .write_values(matrix, SHEET, self.first_row, usage)
+            .await?;
+        Ok((rows, cells, cleared))
+    }
+
+    /* Wipe the old data area, from the first data row to the last of the `squad_rows` rows of
+     * the tab, according to the clear mode. Returns the request and the number of cells it
+     * clears.
+     */
+    fn clear_request(&self, squad_id: i64, squad_rows: usize) -> (Request, usize) {
+        let first = (self.first_row - 1).min(squad_rows);
+        let clear = request(json!({"updateCells": {
+            "range": grid_range(squad_id, first..squad_rows, 0..COLUMNS),
+            "fields": self.clear_mode.fields(),
+        }}));
+        (clear, (squad_rows - first) * COLUMNS)
+    }
+
+    /* Write into a hidden staging tab, verify, then clear the Squad data, copy the staged values
+     * over and drop the staging tab in a single batchUpdate, which the API applies atomically.
+     */
+    async fn upload_staged(
+        &self,
+        matrix: Vec<Vec<String>>,
+        usage: &mut ApiUsage,
+    ) -> anyhow::Result<(usize, usize, usize)> {
+        let rows = matrix.len();
+
+        let metadata = self.sheets.get(self.spreadsheet, false, &[]).await?;
+        usage.record_read(&metadata.body);
+        let (squad_id, squad_rows) = squad_tab(&metadata.body)?;
+
+        /* Left over from an earlier run that died halfway. */
+        if let Some(old_staging) = sheet_id(&metadata.body, STAGING_SHEET) {
+            let delete = request(json!({"deleteSheet": {"sheetId": old_staging}}));
+            batch_update(self.sheets, self.spreadsheet, vec![delete], usage).await?;
+        }
+
+        let add = request(json!({"addSheet": {"properties": {
+            "title": STAGING_SHEET,
+            "hidden": true,
+            "gridProperties": {"rowCount": rows, "columnCount": COLUMNS},
+        }}}));
+        let added = batch_update(self.sheets, self.spreadsheet, vec![add], usage).await?;
+        let staging_id = added
+            .replies
+            .first()
+            .and_then(|reply| reply.add_sheet.as_ref())
+            .and_then(|add| add.properties.as_ref())
+            .map(|props| props.sheet_id)
+            .ok_or_else(|| anyhow!("API did not return the ID of the staging tab"))?;
+        println!("Staging data in hidden tab {}", STAGING_SHEET);
+
+        let result = self
+            .stage_and_swap(matrix, squad_id, squad_rows, staging_id, usage)
+            .await;
+        if result.is_err() {
+            /* Roll back: the Squad tab is untouched, only the staging tab has to go. */
+            let delete = request(json!({"deleteSheet": {"sheetId": staging_id}}));
+            if let Err(e) = batch_update(self.sheets, self.spreadsheet, vec![delete], usage).await {
+                eprintln!("Could not remove staging tab {}: {}", STAGING_SHEET, e);
+            }
+        }
+        result
+    }
+
+    async fn stage_and_swap(
+        &self,
+        matrix: Vec<Vec<String>>,
+        squad_id: i64,
+        squad_rows: usize,
+        staging_id: i64,
+        usage: &mut ApiUsage,
+    ) -> anyhow::Result<(usize, usize, usize)> {
+        let rows = matrix.len();
+        let cells: usize = matrix.iter().map(|row| row.len()).sum();
+
+        let written = self.write_values(matrix, STAGING_SHEET, 1, usage).await?;
+        if written != (rows, cells) {
+            bail!(
+                "staging tab holds {} rows/{} cells, expected {} rows/{} cells; Squad left unchanged",
+                written.0,
+                written.1,
+                rows,
+                cells
+            );
+        }
+
+        let first = self.first_row - 1;
+        let (clear, cleared) = self.clear_request(squad_id, squad_rows);
+        let mut swap = vec![clear];
+        /* copyPaste doesn't grow the tab, unlike the values API in direct mode */
+        if first + rows > squad_rows {
+            swap.push(request(json!({"appendDimension": {
+                "sheetId": squad_id,
+                "dimension": "ROWS",
+                "length": first + rows - squad_rows,
+            }})));
+        }
+        let paste = |paste_type: &str| {
+            request(json!({"copyPaste": {
+                "source": grid_range(staging_id, 0..rows, 0..COLUMNS),
+                "destination": grid_range(squad_id, first..first + rows, 0..COLUMNS),
+                "pasteType": paste_type,
+            }}))
+        };
+        swap.push(paste("PASTE_VALUES"));
+        /* With the formats gone, bring along the number formats (percent, dates, ...) Sheets
+         * inferred on the staging tab, like a direct upload gets them.
+         */
+        if self.clear_mode == ClearMode::All {
+            swap.push(paste("PASTE_FORMAT"));
+        }
+        swap.push(request(json!({"deleteSheet": {"sheetId": staging_id}})));
+        batch_update(self.sheets, self.spreadsheet, swap, usage).await?;
+        println!("Replaced old data with staged upload");
+
+        Ok((written.0, written.1, cleared))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cells_in_range() {
+        assert_eq!(range_cells("Squad!A2:AX58"), Some(57 * 50));
+        assert_eq!(range_cells("'My Squad'!B3:C4"), Some(4));
+        assert_eq!(range_cells("A1:A1"), Some(1));
+        assert_eq!(range_cells("Squad!B3"), Some(1));
+    }
+
+    #[test]
+    fn cells_in_odd_ranges() {
+        assert_eq!(range_cells("Squad!A2:AX"), None);
+        assert_eq!(range_cells("Squad!A:AX"), None);
+        assert_eq!(range_cells("Squad!C5:A1"), None);
+        assert_eq!(range_cells(""), None);
+    }
+
+    fn uploader(
+        sheets: &Spreadsheets,
+        first_row: usize,
+        clear_mode: ClearMode,
+    ) -> DataUploader<'_> {
+        DataUploader::new(sheets, "spreadsheet", first_row).clear_mode(clear_mode)
+    }
+
+    #[test]
+    fn clear_below_the_headers() {
+        let client = sheets::Client::new("id", "secret", "http://localhost", "token", "refresh");
+        let sheets = Spreadsheets { client };
+
+        let (request, cells) = uploader(&sheets, 2, ClearMode::All).clear_request(7, 100);
+        assert_eq!(cells, 99 * COLUMNS);
+        let request = serde_json::to_value(request).unwrap();
+        let update = &request["updateCells"];
+        assert_eq!(update["range"]["sheetId"], 7);
+        assert_eq!(update["range"]["startRowIndex"], 1);
+        assert_eq!(update["range"]["endRowIndex"], 100);
+        assert_eq!(update["fields"], "userEnteredValue,note,userEnteredFormat");
+
+        let (_, cells) = uploader(&sheets, 1, ClearMode::Values).clear_request(7, 10);
+        assert_eq!(cells, 10 * COLUMNS);
+        /* Headers reaching past the end of the tab leave nothing to clear */
+        let (_, cells) = uploader(&sheets, 20, ClearMode::Values).clear_request(7, 10);
+        assert_eq!(cells, 0);
+    }
+}
@@ -0,0 +1,41 @@
+//! Per-run bookkeeping of Sheets API traffic so heavy users can keep an eye on their quota.
+//!
+//! Sheets quotas count requests, so `reads` and `writes` are what to compare against them. Byte
+//! counts are approximate payload sizes, taken by serializing the request and response types
+//! again rather than from the bytes actually on the wire.
+
+#[derive(Debug, Default, serde::Serialize)]
+pub struct ApiUsage {
+    pub reads: u32,
+    pub writes: u32,
+    pub bytes_sent: usize,
+    pub bytes_received: usize,
+}
+
+fn json_len<T: serde::Serialize>(value: &T) -> usize {
+    serde_json::to_vec(value).map(|v| v.len()).unwrap_or(0)
+}
+
+impl ApiUsage {
+    pub fn record_read<R: serde::Serialize>(&mut self, response: &R) {
+        self.reads += 1;
+        self.bytes_received += json_len(response);
+    }
+
+    pub fn record_write<B: serde::Serialize, R: serde::Serialize>(
+        &mut self,
+        body: &B,
+        response: &R,
+    ) {
+        self.writes += 1;
+        self.bytes_sent += json_len(body);
+        self.bytes_received += json_len(response);
+    }
+
+    pub fn print_summary(&self) {
+        println!(
+            "Sheets API usage: {} read(s), {} write(s), {} bytes sent, {} bytes received",
+            self.reads, self.writes, self.bytes_sent, self.bytes_received
+        );
+    }
+}